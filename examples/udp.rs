@@ -14,36 +14,19 @@ pub enum Packet {
 }
 
 fn main() {
-    use std::net::UdpSocket;
-
-    let socket = UdpSocket::bind("127.0.0.1:34254").unwrap();
-    socket.connect("127.0.0.1:53111").unwrap();
-
     let settings = protocol::Settings::default();
-    let mut pipeline = protocol::wire::dgram::Pipeline::new(protocol::wire::middleware::pipeline::default(), settings);
+    let mut endpoint = protocol::wire::dgram::Endpoint::bind("127.0.0.1:34254", |_| protocol::wire::middleware::pipeline::default(), settings).unwrap();
 
+    let peer = "127.0.0.1:53111".parse().unwrap();
 
     // Send some data.
-    {
-        let mut send_packet = |packet| {
-            let mut buffer = std::io::Cursor::new(Vec::new());
-            pipeline.send_to(&mut buffer, &packet).unwrap();
-            socket.send(&buffer.into_inner()).unwrap();
-        };
-
-        send_packet(Packet::Handshake(Handshake));
-        send_packet(Packet::Hello(Hello { id: 51, data: vec![ 1, 2, 3]}));
-    }
+    endpoint.send_to(&Packet::Handshake(Handshake), peer).unwrap();
+    endpoint.send_to(&Packet::Hello(Hello { id: 51, data: vec![ 1, 2, 3]}), peer).unwrap();
 
     loop {
-        let mut buffer = [0u8; 10000];
-        let bytes_read = socket.recv(&mut buffer).unwrap();
-        let mut data = std::io::Cursor::new(&buffer[0..bytes_read]);
-
-        let response = pipeline.receive_from(&mut data).unwrap();
+        let (from, response) = endpoint.receive().unwrap();
 
-        println!("{:?}", response);
+        println!("{}: {:?}", from, response);
         break;
     }
 }
-
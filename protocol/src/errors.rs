@@ -62,6 +62,7 @@ error_chain! {
         CharTryFromError(CharTryFromError);
 
         UuidParseError(::uuid::Error) #[cfg(feature = "uuid")];
+        Tls(::rustls::Error) #[cfg(feature = "tls")];
     }
 
     errors {
@@ -80,6 +81,61 @@ error_chain! {
             description("unimplemented parcel")
             display("unimplemented parcel type '{}'", type_name)
         }
+
+        /// A frame could not be interpreted by a middleware.
+        MalformedFrame(reason: &'static str) {
+            description("malformed frame")
+            display("malformed frame: {}", reason)
+        }
+
+        /// A datagram could not be interpreted.
+        MalformedDatagram(reason: &'static str) {
+            description("malformed datagram")
+            display("malformed datagram: {}", reason)
+        }
+
+        /// A packet is larger than the maximum permitted size.
+        PacketTooLarge(size: usize, limit: usize) {
+            description("packet too large")
+            display("packet of {} bytes exceeds the limit of {} bytes", size, limit)
+        }
+
+        DecryptionFailed {
+            description("frame could not be decrypted")
+            display("frame could not be decrypted, it was either tampered with or encrypted with a different key")
+        }
+
+        ChecksumMismatch {
+            description("checksum mismatch")
+            display("frame checksum did not match, it was corrupted in transit")
+        }
+
+        MacMismatch {
+            description("message authentication code mismatch")
+            display("frame failed authentication, it was either tampered with or signed with a different key")
+        }
+
+        ReplayedFrame(sequence: u64) {
+            description("received a replayed frame")
+            display("received frame {} more than once or too late", sequence)
+        }
+
+        InvalidServerName(name: String) {
+            description("invalid TLS server name")
+            display("'{}' is not a valid TLS server name", name)
+        }
+
+        /// A datagram endpoint already knows as many peers as it may.
+        TooManyPeers(limit: usize) {
+            description("too many peers")
+            display("cannot talk to a new peer, the limit of {} peers has been reached", limit)
+        }
+
+        /// Sending would exceed a rate limit.
+        RateLimitExceeded(wait: std::time::Duration) {
+            description("rate limit exceeded")
+            display("sending would exceed the rate limit, try again in {:?}", wait)
+        }
     }
 }
 
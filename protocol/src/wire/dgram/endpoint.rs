@@ -0,0 +1,302 @@
+use crate::{wire::dgram::Pipeline, wire::middleware, Error, ErrorKind, Parcel, Settings};
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::hash::Hash;
+use std::{fmt, io};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+/// The largest payload a UDP datagram can carry.
+pub(super) const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// The default number of peers an endpoint keeps middleware for.
+pub const DEFAULT_MAX_PEERS: usize = 1024;
+
+/// A datagram endpoint that owns a UDP socket.
+///
+/// Every peer the endpoint talks to gets its own middleware pipeline,
+/// created by a closure the first time the peer is seen. This allows
+/// stateful middleware such as encryption or compression contexts to be
+/// set up per peer. Middleware that holds a key should be given a
+/// different key for every peer, as peers sharing a key and nonce
+/// counter would reuse nonces.
+///
+/// Anyone can send a datagram from a new address, so the number of
+/// peers is limited, to `DEFAULT_MAX_PEERS` unless changed with
+/// `set_max_peers`. Datagrams from new peers beyond the limit fail to
+/// be received with `ErrorKind::TooManyPeers`.
+///
+/// # Example
+///
+/// ```
+/// use protocol::wire::{dgram, middleware};
+///
+/// let settings = protocol::Settings::default();
+/// let mut server = dgram::Endpoint::<String, _>::bind("127.0.0.1:0", |_| middleware::pipeline::default(), settings.clone()).unwrap();
+/// let mut client = dgram::Endpoint::<String, _>::bind("127.0.0.1:0", |_| middleware::pipeline::default(), settings).unwrap();
+///
+/// client.send_to(&"hello".to_owned(), server.local_addr().unwrap()).unwrap();
+///
+/// let (from, packet) = server.receive().unwrap();
+/// assert_eq!(from, client.local_addr().unwrap());
+/// assert_eq!(packet, "hello");
+/// ```
+#[derive(Debug)]
+pub struct Endpoint<P: Parcel, M: middleware::Pipeline>
+{
+    pub socket: UdpSocket,
+    pub settings: Settings,
+
+    new_peer: NewPeer<SocketAddr, M>,
+    peers: HashMap<SocketAddr, Pipeline<P, M>>,
+    max_peers: usize,
+    send_buffer: Vec<u8>,
+    receive_buffer: Vec<u8>,
+}
+
+/// Creates the middleware of a peer from its address.
+pub(super) struct NewPeer<A, M>(Box<dyn FnMut(&A) -> M + Send>);
+
+impl<P,M> Endpoint<P,M>
+    where P: Parcel, M: middleware::Pipeline
+{
+    /// Creates a new endpoint from a socket.
+    ///
+    /// `new_peer_middleware` is called with the address of every new peer.
+    pub fn new<F>(socket: UdpSocket,
+                  new_peer_middleware: F,
+                  settings: Settings) -> Self
+        where F: FnMut(&SocketAddr) -> M + Send + 'static {
+        Endpoint {
+            socket,
+            settings,
+            new_peer: NewPeer::new(new_peer_middleware),
+            peers: HashMap::new(),
+            max_peers: DEFAULT_MAX_PEERS,
+            send_buffer: Vec::new(),
+            receive_buffer: vec![0; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    /// Creates a new endpoint bound to an address.
+    pub fn bind<A,F>(address: A,
+                     new_peer_middleware: F,
+                     settings: Settings) -> Result<Self, Error>
+        where A: ToSocketAddrs, F: FnMut(&SocketAddr) -> M + Send + 'static {
+        Ok(Endpoint::new(UdpSocket::bind(address)?, new_peer_middleware, settings))
+    }
+
+    /// Sends a packet to a peer.
+    pub fn send_to(&mut self,
+                   packet: &P,
+                   address: SocketAddr)
+        -> Result<(), Error> {
+        self.send_buffer.clear();
+        let pipeline = peer_pipeline(&mut self.peers, &mut self.new_peer, self.max_peers, &self.settings, address)?;
+        pipeline.send_into(&mut self.send_buffer, packet)?;
+
+        self.socket.send_to(&self.send_buffer, address)?;
+        Ok(())
+    }
+
+    /// Receives a packet from any peer.
+    ///
+    /// Blocks unless the socket is in non-blocking mode.
+    pub fn receive(&mut self) -> Result<(SocketAddr, P), Error> {
+        let (bytes_read, address) = self.socket.recv_from(&mut self.receive_buffer)?;
+
+        let datagram = &self.receive_buffer[..bytes_read];
+        let pipeline = peer_pipeline(&mut self.peers, &mut self.new_peer, self.max_peers, &self.settings, address)?;
+        let packet = pipeline.receive_from_slice(datagram)?;
+
+        Ok((address, packet))
+    }
+
+    /// Attempts to receive a packet from any peer.
+    ///
+    /// Returns `Ok(None)` if the socket is in non-blocking mode and no
+    /// datagram is available.
+    pub fn try_receive(&mut self) -> Result<Option<(SocketAddr, P)>, Error> {
+        match self.receive() {
+            Ok(received) => Ok(Some(received)),
+            Err(Error(crate::ErrorKind::Io(ref e), _)) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets the address the socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Gets the middleware used for a peer, if the peer is known.
+    pub fn peer_middleware(&self, address: &SocketAddr) -> Option<&M> {
+        self.peers.get(address).map(|pipeline| &pipeline.middleware)
+    }
+
+    /// Gets the middleware used for a peer.
+    ///
+    /// The peer's middleware is created if it has not been seen yet,
+    /// which fails if the endpoint already knows as many peers as it may.
+    pub fn peer_middleware_mut(&mut self, address: SocketAddr) -> Result<&mut M, Error> {
+        Ok(&mut self.peer(address)?.middleware)
+    }
+
+    /// Sets the largest number of peers to keep middleware for.
+    ///
+    /// Peers that are already known are kept even if there are more of
+    /// them than the new limit.
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.max_peers = max_peers;
+    }
+
+    /// Gets the largest number of peers to keep middleware for.
+    pub fn max_peers(&self) -> usize {
+        self.max_peers
+    }
+
+    /// Forgets a peer and its middleware state.
+    ///
+    /// Peers are remembered until they are removed, so long running
+    /// servers should remove peers that disconnect.
+    pub fn remove_peer(&mut self, address: &SocketAddr) -> Option<M> {
+        self.peers.remove(address).map(|pipeline| pipeline.middleware)
+    }
+
+    /// Gets the addresses of all known peers.
+    pub fn peers(&self) -> impl Iterator<Item=&SocketAddr> {
+        self.peers.keys()
+    }
+
+    pub fn into_inner(self) -> UdpSocket { self.socket }
+
+    fn peer(&mut self, address: SocketAddr) -> Result<&mut Pipeline<P, M>, Error> {
+        peer_pipeline(&mut self.peers, &mut self.new_peer, self.max_peers, &self.settings, address)
+    }
+}
+
+impl<A,M> NewPeer<A,M>
+{
+    pub(super) fn new<F>(new_peer_middleware: F) -> Self
+        where F: FnMut(&A) -> M + Send + 'static {
+        NewPeer(Box::new(new_peer_middleware))
+    }
+}
+
+impl<A,M> fmt::Debug for NewPeer<A,M>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("NewPeer").finish_non_exhaustive()
+    }
+}
+
+/// Looks up the pipeline of a peer, creating it if the peer has not
+/// been seen yet and there are fewer than `max_peers` peers.
+///
+/// Takes the endpoint's fields separately so that its send and
+/// receive buffers can stay borrowed.
+pub(super) fn peer_pipeline<'a, A, P, M>(peers: &'a mut HashMap<A, Pipeline<P, M>>,
+                                         new_peer: &mut NewPeer<A, M>,
+                                         max_peers: usize,
+                                         settings: &Settings,
+                                         address: A)
+    -> Result<&'a mut Pipeline<P, M>, Error>
+    where A: Hash + Eq, P: Parcel, M: middleware::Pipeline {
+    let peer_count = peers.len();
+
+    match peers.entry(address) {
+        Entry::Occupied(entry) => Ok(entry.into_mut()),
+        Entry::Vacant(_) if peer_count >= max_peers => Err(ErrorKind::TooManyPeers(max_peers).into()),
+        Entry::Vacant(entry) => {
+            let middleware = (new_peer.0)(entry.key());
+            Ok(entry.insert(Pipeline::new(middleware, settings.clone())))
+        },
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Endpoint;
+    use crate::wire::middleware::rotate_bytes::RotateBytes;
+    use crate::Settings;
+    use std::net::SocketAddr;
+
+    crate::define_middleware_pipeline!(Rotating {
+        rotate: RotateBytes
+    });
+
+    fn endpoint(amount: u8) -> Endpoint<String, Rotating> {
+        Endpoint::bind("127.0.0.1:0", move |_| Rotating { rotate: RotateBytes { amount } }, Settings::default()).unwrap()
+    }
+
+    #[test]
+    fn packets_are_received_with_their_source() {
+        let (mut a, mut b) = (endpoint(0), endpoint(0));
+        let b_address = b.local_addr().unwrap();
+
+        a.send_to(&"ping".to_owned(), b_address).unwrap();
+        let (from, packet) = b.receive().unwrap();
+
+        assert_eq!(from, a.local_addr().unwrap());
+        assert_eq!(packet, "ping");
+        assert_eq!(b.peers().collect::<Vec<_>>(), vec![&from]);
+    }
+
+    #[test]
+    fn middleware_is_created_per_peer() {
+        let (mut plain, mut rotated, mut changed) = (endpoint(0), endpoint(13), endpoint(7));
+        let rotated_address = rotated.local_addr().unwrap();
+        let changed_address = changed.local_addr().unwrap();
+
+        let mut server = Endpoint::<String, _>::bind("127.0.0.1:0", move |address: &SocketAddr| {
+            let amount = if *address == rotated_address { 13 } else { 0 };
+            Rotating { rotate: RotateBytes { amount } }
+        }, Settings::default()).unwrap();
+        let server_address = server.local_addr().unwrap();
+
+        server.peer_middleware_mut(changed_address).unwrap().rotate.amount = 7;
+
+        for (client, message) in [(&mut plain, "plain"), (&mut rotated, "rotated"), (&mut changed, "changed")] {
+            client.send_to(&message.to_owned(), server_address).unwrap();
+            assert_eq!(server.receive().unwrap().1, message);
+        }
+
+        assert_eq!(server.peer_middleware(&plain.local_addr().unwrap()).unwrap().rotate.amount, 0);
+        assert_eq!(server.remove_peer(&rotated_address).unwrap().rotate.amount, 13);
+        assert_eq!(server.peers().count(), 2);
+    }
+
+    #[test]
+    fn new_peers_are_refused_once_the_limit_is_reached() {
+        use crate::{Error, ErrorKind};
+
+        let (mut a, mut b, mut server) = (endpoint(0), endpoint(0), endpoint(0));
+        let server_address = server.local_addr().unwrap();
+        server.set_max_peers(1);
+
+        a.send_to(&"first".to_owned(), server_address).unwrap();
+        b.send_to(&"second".to_owned(), server_address).unwrap();
+        a.send_to(&"third".to_owned(), server_address).unwrap();
+
+        assert_eq!(server.receive().unwrap().1, "first");
+        match server.receive() {
+            Err(Error(ErrorKind::TooManyPeers(1), _)) => (),
+            result => panic!("a peer beyond the limit was accepted: {:?}", result),
+        }
+        assert_eq!(server.receive().unwrap().1, "third");
+        assert_eq!(server.peers().count(), 1);
+
+        // Removing a peer makes room for another.
+        server.remove_peer(&a.local_addr().unwrap());
+        assert!(server.peer_middleware_mut(b.local_addr().unwrap()).is_ok());
+        assert!(server.peer_middleware_mut(a.local_addr().unwrap()).is_err());
+    }
+
+    #[test]
+    fn try_receive_does_not_block() {
+        let mut endpoint = endpoint(0);
+        endpoint.socket.set_nonblocking(true).unwrap();
+
+        assert!(endpoint.try_receive().unwrap().is_none());
+    }
+}
@@ -0,0 +1,516 @@
+//! Splitting of oversized packets into MTU-sized datagrams.
+//!
+//! A [Fragmenter] splits encoded packets into fragments that each fit
+//! within a maximum transmission unit. Every fragment starts with a
+//! small header identifying the packet it belongs to, its index and
+//! the total number of fragments. Packets that already fit are sent
+//! as a single fragment.
+//!
+//! A [Reassembler] collects fragments on the receiving side. Fragments
+//! of several packets may arrive interleaved and in any order.
+//! Incomplete packets are discarded once they time out or when the
+//! memory limit would otherwise be exceeded.
+//!
+//! # Example
+//!
+//! ```
+//! use protocol::wire::{dgram::{self, fragment}, middleware};
+//! use std::time::Instant;
+//!
+//! let settings = protocol::Settings::default();
+//! let mut sender = dgram::Pipeline::<Vec<u8>, _>::new(middleware::pipeline::default(), settings.clone());
+//! let mut receiver = dgram::Pipeline::<Vec<u8>, _>::new(middleware::pipeline::default(), settings);
+//!
+//! let mut fragmenter = fragment::Fragmenter::new(1200);
+//! let mut reassembler = fragment::Reassembler::new(fragment::Limits::default());
+//!
+//! let packet = vec![42u8; 5000];
+//! let datagrams = sender.send_fragmented(&packet, &mut fragmenter).unwrap();
+//! assert_eq!(datagrams.len(), 5);
+//!
+//! let now = Instant::now();
+//! let mut received = None;
+//! for datagram in datagrams.iter().rev() {
+//!     received = receiver.receive_fragment(datagram, &mut reassembler, now).unwrap();
+//! }
+//! assert_eq!(received, Some(packet));
+//! ```
+
+use crate::{Error, ErrorKind, Parcel, Settings};
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// The size of the header at the start of every fragment.
+pub const HEADER_SIZE: usize = 6;
+
+/// The maximum number of fragments a single packet can be split into.
+pub const MAX_FRAGMENTS: usize = u16::MAX as usize;
+
+/// Splits packets into fragments.
+#[derive(Clone, Debug)]
+pub struct Fragmenter {
+    /// The maximum size of a datagram, including the fragment header.
+    pub mtu: usize,
+    next_packet_id: u16,
+}
+
+/// Limits placed on the receiving side of fragmentation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// How long to wait for the remaining fragments of a packet
+    /// after its first fragment arrives.
+    pub timeout: Duration,
+    /// The maximum size of a single reassembled packet.
+    pub max_packet_size: usize,
+    /// The maximum number of bytes buffered across all incomplete packets,
+    /// including the bookkeeping for their fragments.
+    pub max_buffered_bytes: usize,
+}
+
+/// Puts fragments back together into packets.
+#[derive(Clone, Debug)]
+pub struct Reassembler {
+    pub limits: Limits,
+    incomplete: HashMap<u16, Incomplete>,
+    buffered_bytes: usize,
+}
+
+/// A packet that has not had all of its fragments received.
+#[derive(Clone, Debug)]
+struct Incomplete {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    bytes: usize,
+    first_received_at: Instant,
+}
+
+/// The header at the start of every fragment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Header {
+    packet_id: u16,
+    index: u16,
+    count: u16,
+}
+
+impl Fragmenter {
+    /// Creates a new fragmenter.
+    ///
+    /// Panics if the MTU does not leave room for any data after the
+    /// fragment header.
+    pub fn new(mtu: usize) -> Self {
+        assert!(mtu > HEADER_SIZE, "the MTU must be larger than the {} byte fragment header", HEADER_SIZE);
+
+        Fragmenter { mtu, next_packet_id: 0 }
+    }
+
+    /// Splits some data into datagrams no larger than the MTU.
+    pub fn fragment(&mut self,
+                    data: &[u8],
+                    settings: &Settings)
+        -> Result<Vec<Vec<u8>>, Error> {
+        let fragment_size = self.mtu - HEADER_SIZE;
+        let count = std::cmp::max(1, data.len().div_ceil(fragment_size));
+
+        if count > MAX_FRAGMENTS {
+            return Err(ErrorKind::PacketTooLarge(data.len(), MAX_FRAGMENTS * fragment_size).into());
+        }
+
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.wrapping_add(1);
+
+        // An empty packet still needs a single fragment.
+        let chunks = data.chunks(fragment_size).chain(if data.is_empty() { Some(&[][..]) } else { None });
+
+        chunks.enumerate().map(|(index, chunk)| {
+            let header = Header { packet_id, index: index as u16, count: count as u16 };
+
+            let mut datagram = Vec::with_capacity(HEADER_SIZE + chunk.len());
+            header.write(&mut datagram, settings)?;
+            datagram.extend_from_slice(chunk);
+            Ok(datagram)
+        }).collect()
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            timeout: Duration::from_secs(5),
+            max_packet_size: 1024 * 1024,
+            max_buffered_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl Reassembler {
+    /// Creates a new reassembler.
+    pub fn new(limits: Limits) -> Self {
+        Reassembler {
+            limits,
+            incomplete: HashMap::new(),
+            buffered_bytes: 0,
+        }
+    }
+
+    /// Accepts a fragment.
+    ///
+    /// Returns the reassembled data once the final fragment of a
+    /// packet has been received.
+    pub fn receive(&mut self,
+                   datagram: &[u8],
+                   settings: &Settings,
+                   now: Instant)
+        -> Result<Option<Vec<u8>>, Error> {
+        self.expire(now);
+
+        let header = Header::read(datagram, settings)?;
+        let data = &datagram[HEADER_SIZE..];
+
+        if data.len() > self.limits.max_packet_size {
+            return Err(ErrorKind::PacketTooLarge(data.len(), self.limits.max_packet_size).into());
+        }
+
+        if header.count == 1 {
+            return Ok(Some(data.to_owned()));
+        }
+
+        if !self.incomplete.contains_key(&header.packet_id) {
+            // Every fragment but the last carries at least one byte.
+            let min_size = header.count as usize - 1;
+            if min_size > self.limits.max_packet_size {
+                return Err(ErrorKind::PacketTooLarge(min_size, self.limits.max_packet_size).into());
+            }
+
+            let table_bytes = Incomplete::table_bytes(header.count as usize);
+            if !self.make_room(table_bytes + data.len(), header.packet_id) {
+                return Ok(None);
+            }
+
+            self.incomplete.insert(header.packet_id, Incomplete {
+                fragments: vec![None; header.count as usize],
+                received: 0,
+                bytes: 0,
+                first_received_at: now,
+            });
+            self.buffered_bytes += table_bytes;
+        }
+
+        let incomplete = &self.incomplete[&header.packet_id];
+
+        if incomplete.fragments.len() != header.count as usize {
+            return Err(ErrorKind::MalformedDatagram("fragment count differs from earlier fragments").into());
+        }
+
+        // A duplicate fragment.
+        if incomplete.fragments[header.index as usize].is_some() {
+            return Ok(None);
+        }
+
+        if incomplete.bytes + data.len() > self.limits.max_packet_size {
+            let size = incomplete.bytes + data.len();
+            self.discard(header.packet_id);
+            return Err(ErrorKind::PacketTooLarge(size, self.limits.max_packet_size).into());
+        }
+
+        if !self.make_room(data.len(), header.packet_id) {
+            return Ok(None);
+        }
+
+        let incomplete = self.incomplete.get_mut(&header.packet_id).unwrap();
+        incomplete.fragments[header.index as usize] = Some(data.to_owned());
+        incomplete.received += 1;
+        incomplete.bytes += data.len();
+        self.buffered_bytes += data.len();
+
+        if incomplete.received == incomplete.fragments.len() {
+            let incomplete = self.incomplete.remove(&header.packet_id).unwrap();
+            self.buffered_bytes -= incomplete.buffered_bytes();
+
+            Ok(Some(incomplete.fragments.into_iter().flatten().flatten().collect()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Gets the number of packets that are partially received.
+    pub fn incomplete(&self) -> usize {
+        self.incomplete.len()
+    }
+
+    /// Gets the number of bytes held by partially received packets,
+    /// including the bookkeeping for their fragments.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Discards packets that have timed out.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.limits.timeout;
+        let expired: Vec<_> = self.incomplete.iter()
+            .filter(|(_, incomplete)| now.duration_since(incomplete.first_received_at) >= timeout)
+            .map(|(&packet_id, _)| packet_id)
+            .collect();
+
+        for packet_id in expired {
+            self.discard(packet_id);
+        }
+    }
+
+    /// Evicts the oldest incomplete packets until `bytes` more can be
+    /// buffered.
+    ///
+    /// Never evicts the packet the bytes belong to. Returns `false` if
+    /// there is still not enough room.
+    fn make_room(&mut self, bytes: usize, packet_id: u16) -> bool {
+        while self.buffered_bytes + bytes > self.limits.max_buffered_bytes {
+            let oldest = self.incomplete.iter()
+                .filter(|&(&id, _)| id != packet_id)
+                .min_by_key(|(_, incomplete)| incomplete.first_received_at)
+                .map(|(&id, _)| id);
+
+            match oldest {
+                Some(oldest) => self.discard(oldest),
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    fn discard(&mut self, packet_id: u16) {
+        if let Some(incomplete) = self.incomplete.remove(&packet_id) {
+            self.buffered_bytes -= incomplete.buffered_bytes();
+        }
+    }
+}
+
+impl Incomplete {
+    /// Gets the memory used by the table of fragments of a packet,
+    /// before any of them arrive.
+    fn table_bytes(count: usize) -> usize {
+        count * std::mem::size_of::<Option<Vec<u8>>>()
+    }
+
+    /// Gets the memory counted against `Limits::max_buffered_bytes`.
+    fn buffered_bytes(&self) -> usize {
+        self.bytes + Incomplete::table_bytes(self.fragments.len())
+    }
+}
+
+impl Header {
+    fn read(datagram: &[u8], settings: &Settings) -> Result<Self, Error> {
+        if datagram.len() < HEADER_SIZE {
+            return Err(ErrorKind::MalformedDatagram("datagram too short for fragment header").into());
+        }
+
+        let mut read = Cursor::new(datagram);
+        let header = Header {
+            packet_id: u16::read(&mut read, settings)?,
+            index: u16::read(&mut read, settings)?,
+            count: u16::read(&mut read, settings)?,
+        };
+
+        if header.count == 0 || header.index >= header.count {
+            return Err(ErrorKind::MalformedDatagram("fragment index out of range").into());
+        }
+
+        Ok(header)
+    }
+
+    fn write(&self, write: &mut Vec<u8>, settings: &Settings) -> Result<(), Error> {
+        self.packet_id.write(write, settings)?;
+        self.index.write(write, settings)?;
+        self.count.write(write, settings)
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Fragmenter, Header, Incomplete, Limits, Reassembler, HEADER_SIZE};
+    use crate::{ErrorKind, Settings};
+    use std::time::{Duration, Instant};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn reassemble(reassembler: &mut Reassembler, datagrams: &[Vec<u8>], now: Instant) -> Option<Vec<u8>> {
+        let mut result = None;
+        for datagram in datagrams {
+            if let Some(packet) = reassembler.receive(datagram, &Settings::default(), now).unwrap() {
+                assert!(result.is_none());
+                result = Some(packet);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn small_packets_are_a_single_fragment() {
+        let datagrams = Fragmenter::new(100).fragment(&data(50), &Settings::default()).unwrap();
+
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(&datagrams[0][HEADER_SIZE..], &data(50)[..]);
+    }
+
+    #[test]
+    fn empty_packets_are_a_single_fragment() {
+        let datagrams = Fragmenter::new(100).fragment(&[], &Settings::default()).unwrap();
+        let mut reassembler = Reassembler::new(Limits::default());
+
+        assert_eq!(reassemble(&mut reassembler, &datagrams, Instant::now()), Some(Vec::new()));
+    }
+
+    #[test]
+    fn fragments_fit_within_mtu() {
+        let datagrams = Fragmenter::new(100).fragment(&data(1000), &Settings::default()).unwrap();
+
+        assert_eq!(datagrams.len(), 11);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= 100));
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+        let mut datagrams = Fragmenter::new(100).fragment(&data(1000), &Settings::default()).unwrap();
+        datagrams.reverse();
+        datagrams.swap(2, 7);
+
+        let mut reassembler = Reassembler::new(Limits::default());
+
+        assert_eq!(reassemble(&mut reassembler, &datagrams, Instant::now()), Some(data(1000)));
+        assert_eq!(reassembler.incomplete(), 0);
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn reassembles_interleaved_packets() {
+        let mut fragmenter = Fragmenter::new(100);
+        let first = fragmenter.fragment(&data(300), &Settings::default()).unwrap();
+        let second = fragmenter.fragment(&vec![9; 300], &Settings::default()).unwrap();
+
+        let mut reassembler = Reassembler::new(Limits::default());
+        let now = Instant::now();
+        let settings = Settings::default();
+
+        let mut received = Vec::new();
+        for (a, b) in first.iter().zip(second.iter()) {
+            received.extend(reassembler.receive(b, &settings, now).unwrap());
+            received.extend(reassembler.receive(a, &settings, now).unwrap());
+        }
+
+        assert_eq!(received, vec![vec![9; 300], data(300)]);
+    }
+
+    #[test]
+    fn duplicate_fragments_are_ignored() {
+        let datagrams = Fragmenter::new(100).fragment(&data(200), &Settings::default()).unwrap();
+        let duplicated = vec![datagrams[0].clone(), datagrams[0].clone(), datagrams[1].clone(), datagrams[2].clone()];
+
+        let mut reassembler = Reassembler::new(Limits::default());
+
+        assert_eq!(reassemble(&mut reassembler, &duplicated, Instant::now()), Some(data(200)));
+    }
+
+    #[test]
+    fn incomplete_packets_time_out() {
+        let datagrams = Fragmenter::new(100).fragment(&data(200), &Settings::default()).unwrap();
+        let mut reassembler = Reassembler::new(Limits { timeout: Duration::from_secs(1), ..Limits::default() });
+        let now = Instant::now();
+
+        assert_eq!(reassemble(&mut reassembler, &datagrams[..2], now), None);
+        assert_eq!(reassembler.incomplete(), 1);
+
+        assert_eq!(reassemble(&mut reassembler, &datagrams[2..], now + Duration::from_secs(1)), None);
+        assert_eq!(reassembler.incomplete(), 1, "the last fragment starts a new incomplete packet");
+    }
+
+    #[test]
+    fn oldest_packets_are_evicted_when_memory_is_exhausted() {
+        let mut fragmenter = Fragmenter::new(100);
+        let first = fragmenter.fragment(&data(300), &Settings::default()).unwrap();
+        let second = fragmenter.fragment(&data(300), &Settings::default()).unwrap();
+
+        let mut reassembler = Reassembler::new(Limits { max_buffered_bytes: 400, ..Limits::default() });
+        let now = Instant::now();
+
+        assert_eq!(reassemble(&mut reassembler, &first[..2], now), None);
+        assert_eq!(reassemble(&mut reassembler, &second[..2], now + Duration::from_millis(1)), None);
+        assert_eq!(reassembler.incomplete(), 1);
+        assert!(reassembler.buffered_bytes() <= 400);
+
+        assert_eq!(reassemble(&mut reassembler, &second[2..], now + Duration::from_millis(2)), Some(data(300)));
+    }
+
+    #[test]
+    fn oversized_packets_are_rejected() {
+        let datagrams = Fragmenter::new(100).fragment(&data(1000), &Settings::default()).unwrap();
+        let mut reassembler = Reassembler::new(Limits { max_packet_size: 500, ..Limits::default() });
+        let now = Instant::now();
+
+        let errors: Vec<_> = datagrams.iter()
+            .filter_map(|datagram| reassembler.receive(datagram, &Settings::default(), now).err())
+            .collect();
+
+        match errors[0].0 {
+            ErrorKind::PacketTooLarge(_, 500) => (),
+            ref e => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(errors.len(), 1);
+        assert!(reassembler.buffered_bytes() <= 500 + Incomplete::table_bytes(datagrams.len()));
+    }
+
+    #[test]
+    fn fragment_tables_count_against_the_memory_limit() {
+        let limits = Limits::default();
+        let mut reassembler = Reassembler::new(limits.clone());
+        let settings = Settings::default();
+        let now = Instant::now();
+
+        for packet_id in 0..100 {
+            let mut datagram = Vec::new();
+            Header { packet_id, index: 0, count: u16::MAX }.write(&mut datagram, &settings).unwrap();
+            datagram.push(0);
+
+            assert_eq!(reassembler.receive(&datagram, &settings, now).unwrap(), None);
+        }
+
+        assert!(reassembler.buffered_bytes() <= limits.max_buffered_bytes);
+        assert!(reassembler.incomplete() < 100);
+    }
+
+    #[test]
+    fn fragment_counts_too_large_for_the_packet_size_are_rejected() {
+        let mut reassembler = Reassembler::new(Limits { max_packet_size: 100, ..Limits::default() });
+        let settings = Settings::default();
+
+        let mut datagram = Vec::new();
+        Header { packet_id: 0, index: 0, count: 1000 }.write(&mut datagram, &settings).unwrap();
+
+        match reassembler.receive(&datagram, &settings, Instant::now()) {
+            Err(crate::Error(ErrorKind::PacketTooLarge(999, 100), _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(reassembler.incomplete(), 0);
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn oversized_single_fragments_are_rejected() {
+        let datagrams = Fragmenter::new(1000).fragment(&data(200), &Settings::default()).unwrap();
+        let mut reassembler = Reassembler::new(Limits { max_packet_size: 100, ..Limits::default() });
+
+        assert_eq!(datagrams.len(), 1);
+        assert!(reassembler.receive(&datagrams[0], &Settings::default(), Instant::now()).is_err());
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let mut reassembler = Reassembler::new(Limits::default());
+
+        assert!(reassembler.receive(&[0, 0, 0], &Settings::default(), Instant::now()).is_err());
+        assert!(reassembler.receive(&[0, 0, 0, 5, 0, 5], &Settings::default(), Instant::now()).is_err());
+    }
+}
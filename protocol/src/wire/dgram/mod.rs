@@ -1,7 +1,15 @@
-use crate::{wire::middleware, Parcel, Error, Settings};
+pub use self::endpoint::{Endpoint, DEFAULT_MAX_PEERS};
+
+mod endpoint;
+pub mod fragment;
+pub mod reliable;
+pub mod sim;
+#[cfg(unix)] pub mod unix;
+
+use crate::{wire::{middleware::{self, frame, Context, Frame, Metadata}, rate_limit::RateLimiter}, Parcel, Error, Settings};
 
 use std::io::prelude::*;
-use std::io::Cursor;
+use std::time::Instant;
 use std;
 
 /// A datagram-based packet pipeline.
@@ -10,6 +18,11 @@ pub struct Pipeline<P: Parcel, M: middleware::Pipeline>
 {
     pub middleware: M,
     pub settings: Settings,
+    /// Limits the rate at which packets are sent, if set.
+    pub rate_limiter: Option<RateLimiter>,
+
+    send_sequence: u64,
+    receive_sequence: u64,
 
     _a: std::marker::PhantomData<P>,
 }
@@ -21,6 +34,9 @@ impl<P,M> Pipeline<P,M>
                 settings: Settings) -> Self {
         Pipeline {
             middleware, settings,
+            rate_limiter: None,
+            send_sequence: 0,
+            receive_sequence: 0,
             _a: std::marker::PhantomData,
         }
     }
@@ -28,19 +44,194 @@ impl<P,M> Pipeline<P,M>
     /// Reads a packet from a buffer which contains a single packet.
     pub fn receive_from(&mut self, buffer: &mut dyn Read)
         -> Result<P, Error> {
-        let raw_bytes: Result<Vec<u8>, _> = buffer.bytes().collect();
-        let raw_bytes = raw_bytes?;
+        let mut raw_bytes = Vec::new();
+        buffer.read_to_end(&mut raw_bytes)?;
+
+        let mut context = self.next_incoming_context();
 
-        let mut bytes = Cursor::new(self.middleware.decode_data(raw_bytes)?);
-        P::read(&mut bytes, &self.settings)
+        if self.middleware.is_noop() {
+            P::from_raw_bytes(&raw_bytes, &self.settings)
+        } else {
+            let mut frame = Frame::from_vec(raw_bytes);
+            self.middleware.decode_with_context(&mut frame, &mut context)?;
+            P::from_raw_bytes(&frame, &self.settings)
+        }
+    }
+
+    /// Reads a packet from a slice which contains a single packet.
+    ///
+    /// The slice is parsed in place when the middleware pipeline
+    /// is a no-op.
+    pub fn receive_from_slice(&mut self, datagram: &[u8])
+        -> Result<P, Error> {
+        let mut context = self.next_incoming_context();
+
+        if self.middleware.is_noop() {
+            P::from_raw_bytes(datagram, &self.settings)
+        } else {
+            let mut frame = Frame::from_vec(datagram.to_owned());
+            self.middleware.decode_with_context(&mut frame, &mut context)?;
+            P::from_raw_bytes(&frame, &self.settings)
+        }
     }
 
     /// Writes a packet into a buffer.
     pub fn send_to(&mut self, buffer: &mut dyn Write, packet: &P)
         -> Result<(), Error> {
-        let bytes = self.middleware.encode_data(packet.raw_bytes(&self.settings)?)?;
-        buffer.write(&bytes)?;
+        let mut bytes = Vec::new();
+        self.send_into(&mut bytes, packet)?;
+
+        buffer.write_all(&bytes)?;
         Ok(())
     }
+
+    /// Appends a packet to a buffer.
+    ///
+    /// The packet is written straight into the buffer when the
+    /// middleware pipeline is a no-op, so clearing and reusing the
+    /// same buffer for every packet avoids allocating.
+    pub fn send_into(&mut self, buffer: &mut Vec<u8>, packet: &P)
+        -> Result<(), Error> {
+        self.send_into_with(buffer, packet, Metadata::new())
+    }
+
+    /// Appends a packet to a buffer, attaching metadata for the
+    /// middleware to see.
+    ///
+    /// With a rate limiter that rejects packets, this fails with
+    /// `ErrorKind::RateLimitExceeded` before anything is encoded.
+    pub fn send_into_with(&mut self,
+                          buffer: &mut Vec<u8>,
+                          packet: &P,
+                          metadata: Metadata)
+        -> Result<(), Error> {
+        if let Some(ref mut rate_limiter) = self.rate_limiter {
+            rate_limiter.acquire()?;
+        }
+
+        let start = buffer.len();
+        let mut context = Context::outgoing(self.send_sequence, P::TYPE_NAME, metadata);
+        self.send_sequence += 1;
+
+        if self.middleware.is_noop() {
+            packet.write(buffer, &self.settings)?;
+        } else {
+            let mut frame = Frame::with_headroom(frame::DEFAULT_HEADROOM);
+            packet.write(&mut frame, &self.settings)?;
+            self.middleware.encode_with_context(&mut frame, &mut context)?;
+
+            buffer.extend_from_slice(&frame);
+        }
+
+        if let Some(ref mut rate_limiter) = self.rate_limiter {
+            rate_limiter.consume(buffer.len() - start);
+        }
+
+        Ok(())
+    }
+
+    /// Writes a packet into as many datagrams as needed to stay
+    /// within the fragmenter's MTU.
+    pub fn send_fragmented(&mut self,
+                           packet: &P,
+                           fragmenter: &mut fragment::Fragmenter)
+        -> Result<Vec<Vec<u8>>, Error> {
+        let mut bytes = Vec::new();
+        self.send_into(&mut bytes, packet)?;
+
+        fragmenter.fragment(&bytes, &self.settings)
+    }
+
+    /// Reads a fragment produced by `send_fragmented`.
+    ///
+    /// Returns the packet once all of its fragments have been received.
+    pub fn receive_fragment(&mut self,
+                            datagram: &[u8],
+                            reassembler: &mut fragment::Reassembler,
+                            now: Instant)
+        -> Result<Option<P>, Error> {
+        match reassembler.receive(datagram, &self.settings, now)? {
+            Some(bytes) => self.receive_from_slice(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_incoming_context(&mut self) -> Context {
+        let context = Context::incoming(self.receive_sequence);
+        self.receive_sequence += 1;
+        context
+    }
 }
 
+
+#[cfg(test)]
+mod test
+{
+    use super::Pipeline;
+    use crate::wire::middleware::{self, rotate_bytes::RotateBytes};
+    use crate::Settings;
+
+    crate::define_middleware_pipeline!(Rotating {
+        rotate: RotateBytes
+    });
+
+    fn pipeline(amount: u8) -> Pipeline<String, Rotating> {
+        Pipeline::new(Rotating { rotate: RotateBytes { amount } }, Settings::default())
+    }
+
+    #[test]
+    fn send_into_appends_to_the_buffer() {
+        let mut buffer = vec![0xff];
+        pipeline(0).send_into(&mut buffer, &"a".to_owned()).unwrap();
+
+        assert_eq!(buffer, vec![0xff, 0, 0, 0, 1, b'a']);
+    }
+
+    #[test]
+    fn slices_read_back_with_and_without_middleware() {
+        for &amount in &[0, 13] {
+            let mut buffer = Vec::new();
+            pipeline(amount).send_into(&mut buffer, &"hello".to_owned()).unwrap();
+
+            assert_eq!(pipeline(amount).receive_from_slice(&buffer).unwrap(), "hello");
+            assert_eq!(pipeline(amount).receive_from(&mut &buffer[..]).unwrap(), "hello");
+        }
+    }
+
+    #[test]
+    fn default_pipeline_is_noop() {
+        use crate::wire::middleware::Pipeline;
+
+        assert!(middleware::pipeline::default().is_noop());
+    }
+
+    #[test]
+    fn sending_is_rate_limited() {
+        use crate::wire::rate_limit::{ManualClock, Mode, RateLimit, RateLimiter};
+        use crate::{Error, ErrorKind};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::new());
+        let limit = RateLimit { bytes_per_second: Some(100), burst: Duration::from_millis(100), ..RateLimit::default() };
+
+        let mut pipeline = pipeline(13);
+        pipeline.rate_limiter = Some(RateLimiter::with_clock(limit, Mode::Reject, clock.clone()));
+
+        // The bucket holds 10 bytes, so the second 9 byte packet takes
+        // it below zero.
+        let mut buffer = Vec::new();
+        pipeline.send_into(&mut buffer, &"hello".to_owned()).unwrap();
+        pipeline.send_into(&mut buffer, &"hello".to_owned()).unwrap();
+
+        match pipeline.send_into(&mut buffer, &"hello".to_owned()) {
+            Err(Error(ErrorKind::RateLimitExceeded(..), _)) => (),
+            result => panic!("rate limit was not enforced: {:?}", result),
+        }
+        assert_eq!(buffer.len(), 18);
+
+        clock.advance(Duration::from_millis(200));
+        pipeline.send_into(&mut buffer, &"hello".to_owned()).unwrap();
+        assert_eq!(pipeline.receive_from_slice(&buffer[18..]).unwrap(), "hello");
+    }
+}
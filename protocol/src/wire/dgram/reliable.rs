@@ -0,0 +1,623 @@
+//! An optional reliability layer on top of datagram pipelines.
+//!
+//! A [Channel] tracks the state of a conversation with a single peer.
+//! Every datagram it produces carries a small header with a sequence
+//! number, the most recent sequence number received from the peer and
+//! a bitfield acknowledging the 32 sequence numbers before that.
+//!
+//! Each packet is sent with a [Delivery] mode.
+//!
+//!   * `Unreliable` packets are sent once and may be lost, duplicated
+//!     or reordered.
+//!   * `ReliableUnordered` packets are retransmitted until acknowledged
+//!     and delivered exactly once, in whatever order they arrive.
+//!   * `ReliableOrdered` packets are retransmitted until acknowledged
+//!     and delivered exactly once, in the order they were sent.
+//!
+//! The channel never touches a socket. It hands out datagrams to send,
+//! accepts datagrams that were received, and is driven by the caller
+//! passing the current time.
+//!
+//! # Example
+//!
+//! ```
+//! use protocol::wire::{dgram::{self, reliable}, middleware};
+//! use std::time::Instant;
+//!
+//! let settings = protocol::Settings::default();
+//! let pipeline = || dgram::Pipeline::<String, _>::new(middleware::pipeline::default(), settings.clone());
+//!
+//! let mut client = reliable::Channel::new(pipeline(), reliable::Config::default());
+//! let mut server = reliable::Channel::new(pipeline(), reliable::Config::default());
+//!
+//! let now = Instant::now();
+//! let datagram = client.send(&"hello".to_owned(), reliable::Delivery::ReliableOrdered, now).unwrap();
+//!
+//! assert_eq!(server.receive(&datagram, now).unwrap(), vec!["hello".to_owned()]);
+//!
+//! // The server has nothing else to say, so it sends a bare acknowledgement.
+//! for datagram in server.update(now).unwrap() {
+//!     client.receive(&datagram, now).unwrap();
+//! }
+//! assert_eq!(client.unacknowledged(), 0);
+//! ```
+
+use crate::{wire::dgram::Pipeline, wire::middleware, Error, ErrorKind, Parcel, Settings};
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// The number of sequence numbers remembered for acknowledgements
+/// and duplicate suppression.
+///
+/// Reliable packets that are more than this many messages behind the
+/// newest message received are assumed to be duplicates, and reliable
+/// ordered packets that are this many messages or more ahead of the
+/// next one to be delivered are left unacknowledged.
+const SEQUENCE_BUFFER_SIZE: usize = 1024;
+
+/// The number of sequence numbers acknowledged in each datagram in
+/// addition to the most recent one.
+const ACK_BITS: u16 = 32;
+
+/// How a packet should be delivered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Delivery {
+    /// Sent once, with no guarantees.
+    Unreliable,
+    /// Retransmitted until acknowledged, delivered in arrival order.
+    ReliableUnordered,
+    /// Retransmitted until acknowledged, delivered in send order.
+    ReliableOrdered,
+}
+
+/// Reliability layer configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// How long to wait for an acknowledgement before a reliable
+    /// packet is sent again.
+    pub retransmit_timeout: Duration,
+}
+
+/// The reliability state of a conversation with a single peer.
+#[derive(Debug)]
+pub struct Channel<P: Parcel, M: middleware::Pipeline>
+{
+    /// The pipeline used to encode and decode packet payloads.
+    pub pipeline: Pipeline<P, M>,
+    pub config: Config,
+
+    /// The sequence number of the next datagram we send.
+    local_sequence: u16,
+    /// The most recent sequence number received from the peer.
+    remote_sequence: Option<u16>,
+    /// The sequence numbers received from the peer, for acknowledgements.
+    received_sequences: SequenceBuffer,
+    /// Whether we have received something that we have not acknowledged yet.
+    ack_pending: bool,
+
+    /// Reliable packets that have been sent but not acknowledged, by
+    /// the sequence number of the datagram that last carried them.
+    unacknowledged: HashMap<u16, Unacknowledged>,
+    next_message_id: HashMap<Delivery, u16>,
+
+    /// The reliable unordered message IDs received so far.
+    received_unordered: SequenceBuffer,
+    /// The next reliable ordered message ID to deliver.
+    next_ordered: u16,
+    /// Reliable ordered packets that arrived ahead of `next_ordered`.
+    ordered_backlog: HashMap<u16, P>,
+
+    round_trip_time: Option<Duration>,
+}
+
+/// A reliable packet that has not been acknowledged yet.
+#[derive(Clone, Debug)]
+struct Unacknowledged {
+    delivery: Delivery,
+    message_id: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+}
+
+/// The header at the start of every datagram.
+#[derive(Clone, Debug, PartialEq)]
+struct Header {
+    sequence: u16,
+    ack: u16,
+    ack_bits: u32,
+    kind: Kind,
+}
+
+/// What follows the header.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Kind {
+    /// Nothing, the datagram only carries acknowledgements.
+    AckOnly,
+    Unreliable,
+    Reliable { delivery: Delivery, message_id: u16 },
+}
+
+/// A ring of recently seen sequence numbers.
+#[derive(Clone, Debug)]
+struct SequenceBuffer {
+    entries: Vec<Option<u16>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            retransmit_timeout: Duration::from_millis(100),
+        }
+    }
+}
+
+impl<P,M> Channel<P,M>
+    where P: Parcel, M: middleware::Pipeline
+{
+    /// Creates a new channel.
+    pub fn new(pipeline: Pipeline<P, M>,
+               config: Config) -> Self {
+        Channel {
+            pipeline,
+            config,
+            local_sequence: 0,
+            remote_sequence: None,
+            received_sequences: SequenceBuffer::new(),
+            ack_pending: false,
+            unacknowledged: HashMap::new(),
+            next_message_id: HashMap::new(),
+            received_unordered: SequenceBuffer::new(),
+            next_ordered: 0,
+            ordered_backlog: HashMap::new(),
+            round_trip_time: None,
+        }
+    }
+
+    /// Sends a packet.
+    ///
+    /// Returns the datagram that should be transmitted to the peer.
+    pub fn send(&mut self,
+                packet: &P,
+                delivery: Delivery,
+                now: Instant)
+        -> Result<Vec<u8>, Error> {
+        let mut payload = Vec::new();
+        self.pipeline.send_into(&mut payload, packet)?;
+
+        let kind = match delivery {
+            Delivery::Unreliable => Kind::Unreliable,
+            delivery => {
+                let next_message_id = self.next_message_id.entry(delivery).or_insert(0);
+                let message_id = *next_message_id;
+                *next_message_id = next_message_id.wrapping_add(1);
+
+                Kind::Reliable { delivery, message_id }
+            },
+        };
+
+        self.write_datagram(kind, payload, now)
+    }
+
+    /// Processes a datagram received from the peer.
+    ///
+    /// Returns the packets that are ready to be delivered, which may
+    /// be none if the datagram was a duplicate or arrived out of order.
+    /// Datagrams whose payload cannot be decoded are not acknowledged.
+    pub fn receive(&mut self,
+                   datagram: &[u8],
+                   now: Instant)
+        -> Result<Vec<P>, Error> {
+        let mut cursor = Cursor::new(datagram);
+        let header = Header::read(&mut cursor, &self.pipeline.settings)?;
+        let payload = &datagram[cursor.position() as usize..];
+
+        self.process_acknowledgements(&header, now);
+
+        // Payloads are decoded before the datagram is acknowledged, so
+        // that one that cannot be accepted yet is sent again rather than
+        // being lost.
+        let packet = match header.kind {
+            Kind::AckOnly => None,
+            Kind::Reliable { delivery: Delivery::ReliableOrdered, message_id } => {
+                let distance = message_id.wrapping_sub(self.next_ordered);
+
+                if distance >= 0x8000 || self.ordered_backlog.contains_key(&message_id) {
+                    // A duplicate of something already received.
+                    None
+                } else if distance as usize >= SEQUENCE_BUFFER_SIZE {
+                    // Too far ahead to buffer. Leaving the datagram
+                    // unacknowledged makes the sender try again later.
+                    return Ok(Vec::new());
+                } else {
+                    Some(self.pipeline.receive_from_slice(payload)?)
+                }
+            },
+            Kind::Reliable { message_id, .. } if !self.received_unordered.is_new(message_id) => None,
+            _ => Some(self.pipeline.receive_from_slice(payload)?),
+        };
+
+        self.received_sequences.insert(header.sequence);
+        if self.remote_sequence.map(|remote| sequence_greater_than(header.sequence, remote)).unwrap_or(true) {
+            self.remote_sequence = Some(header.sequence);
+        }
+
+        match header.kind {
+            Kind::AckOnly | Kind::Unreliable => Ok(packet.into_iter().collect()),
+            Kind::Reliable { delivery: Delivery::ReliableOrdered, message_id } => {
+                self.ack_pending = true;
+                Ok(self.receive_ordered(message_id, packet))
+            },
+            Kind::Reliable { message_id, .. } => {
+                self.ack_pending = true;
+
+                if packet.is_some() {
+                    self.received_unordered.insert(message_id);
+                }
+                Ok(packet.into_iter().collect())
+            },
+        }
+    }
+
+    /// Retransmits reliable packets whose acknowledgements are overdue.
+    ///
+    /// Should be called regularly. Returns the datagrams that should be
+    /// transmitted to the peer. If received reliable packets have not
+    /// been acknowledged by an outgoing datagram yet, a datagram carrying
+    /// only acknowledgements is included.
+    pub fn update(&mut self, now: Instant)
+        -> Result<Vec<Vec<u8>>, Error> {
+        let mut overdue: Vec<_> = self.unacknowledged.iter()
+            .filter(|(_, unacknowledged)| now.duration_since(unacknowledged.sent_at) >= self.config.retransmit_timeout)
+            .map(|(&sequence, unacknowledged)| (unacknowledged.sent_at, sequence))
+            .collect();
+        overdue.sort();
+
+        let mut datagrams = Vec::new();
+
+        for (_, sequence) in overdue {
+            let unacknowledged = self.unacknowledged.remove(&sequence).unwrap();
+            let kind = Kind::Reliable { delivery: unacknowledged.delivery, message_id: unacknowledged.message_id };
+
+            datagrams.push(self.write_datagram(kind, unacknowledged.payload, now)?);
+        }
+
+        if self.ack_pending {
+            datagrams.push(self.write_datagram(Kind::AckOnly, Vec::new(), now)?);
+        }
+
+        Ok(datagrams)
+    }
+
+    /// Gets the number of reliable packets waiting to be acknowledged.
+    pub fn unacknowledged(&self) -> usize {
+        self.unacknowledged.len()
+    }
+
+    /// Gets the smoothed round trip time, once at least one reliable
+    /// packet has been acknowledged.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.round_trip_time
+    }
+
+    fn write_datagram(&mut self,
+                      kind: Kind,
+                      payload: Vec<u8>,
+                      now: Instant)
+        -> Result<Vec<u8>, Error> {
+        let sequence = self.local_sequence;
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+
+        let (ack, ack_bits) = self.acknowledgements();
+        let header = Header { sequence, ack, ack_bits, kind };
+
+        let mut datagram = Vec::new();
+        header.write(&mut datagram, &self.pipeline.settings)?;
+        datagram.extend_from_slice(&payload);
+
+        if let Kind::Reliable { delivery, message_id } = kind {
+            self.unacknowledged.insert(sequence, Unacknowledged { delivery, message_id, payload, sent_at: now });
+        }
+
+        self.ack_pending = false;
+        Ok(datagram)
+    }
+
+    /// Builds the acknowledgement fields of an outgoing header.
+    fn acknowledgements(&self) -> (u16, u32) {
+        let ack = match self.remote_sequence {
+            Some(remote_sequence) => remote_sequence,
+            // Nothing received yet, acknowledge a sequence number that
+            // can never be outstanding.
+            None => return (self.local_sequence.wrapping_sub(0x8000), 0),
+        };
+
+        let ack_bits = (1..=ACK_BITS)
+            .filter(|&i| self.received_sequences.contains(ack.wrapping_sub(i)))
+            .fold(0u32, |bits, i| bits | (1 << (i - 1)));
+
+        (ack, ack_bits)
+    }
+
+    fn process_acknowledgements(&mut self, header: &Header, now: Instant) {
+        let acknowledged = (1..=ACK_BITS)
+            .filter(|&i| header.ack_bits & (1 << (i - 1)) != 0)
+            .map(|i| header.ack.wrapping_sub(i))
+            .chain(Some(header.ack));
+
+        for sequence in acknowledged {
+            if let Some(unacknowledged) = self.unacknowledged.remove(&sequence) {
+                let sample = now.duration_since(unacknowledged.sent_at);
+
+                self.round_trip_time = Some(match self.round_trip_time {
+                    Some(rtt) => (rtt * 7 + sample) / 8,
+                    None => sample,
+                });
+            }
+        }
+    }
+
+    /// Accepts a reliable ordered packet and returns any packets that
+    /// can now be delivered in order.
+    ///
+    /// Duplicates are passed as `None`.
+    fn receive_ordered(&mut self, message_id: u16, packet: Option<P>) -> Vec<P> {
+        if let Some(packet) = packet {
+            self.ordered_backlog.insert(message_id, packet);
+        }
+
+        let mut deliverable = Vec::new();
+        while let Some(packet) = self.ordered_backlog.remove(&self.next_ordered) {
+            deliverable.push(packet);
+            self.next_ordered = self.next_ordered.wrapping_add(1);
+        }
+
+        deliverable
+    }
+}
+
+impl Header {
+    const ACK_ONLY: u8 = 0;
+    const UNRELIABLE: u8 = 1;
+    const RELIABLE_UNORDERED: u8 = 2;
+    const RELIABLE_ORDERED: u8 = 3;
+
+    fn read(read: &mut Cursor<&[u8]>, settings: &Settings) -> Result<Self, Error> {
+        let sequence = u16::read(read, settings)?;
+        let ack = u16::read(read, settings)?;
+        let ack_bits = u32::read(read, settings)?;
+
+        let kind = match u8::read(read, settings)? {
+            Header::ACK_ONLY => Kind::AckOnly,
+            Header::UNRELIABLE => Kind::Unreliable,
+            Header::RELIABLE_UNORDERED => Kind::Reliable {
+                delivery: Delivery::ReliableUnordered,
+                message_id: u16::read(read, settings)?,
+            },
+            Header::RELIABLE_ORDERED => Kind::Reliable {
+                delivery: Delivery::ReliableOrdered,
+                message_id: u16::read(read, settings)?,
+            },
+            _ => return Err(ErrorKind::MalformedDatagram("unknown reliability header kind").into()),
+        };
+
+        Ok(Header { sequence, ack, ack_bits, kind })
+    }
+
+    fn write(&self, write: &mut Vec<u8>, settings: &Settings) -> Result<(), Error> {
+        self.sequence.write(write, settings)?;
+        self.ack.write(write, settings)?;
+        self.ack_bits.write(write, settings)?;
+
+        match self.kind {
+            Kind::AckOnly => Header::ACK_ONLY.write(write, settings),
+            Kind::Unreliable => Header::UNRELIABLE.write(write, settings),
+            Kind::Reliable { delivery, message_id } => {
+                let kind = match delivery {
+                    Delivery::ReliableOrdered => Header::RELIABLE_ORDERED,
+                    _ => Header::RELIABLE_UNORDERED,
+                };
+
+                kind.write(write, settings)?;
+                message_id.write(write, settings)
+            },
+        }
+    }
+}
+
+impl SequenceBuffer {
+    fn new() -> Self {
+        SequenceBuffer { entries: vec![None; SEQUENCE_BUFFER_SIZE] }
+    }
+
+    fn insert(&mut self, sequence: u16) {
+        self.entries[sequence as usize % SEQUENCE_BUFFER_SIZE] = Some(sequence);
+    }
+
+    fn contains(&self, sequence: u16) -> bool {
+        self.entries[sequence as usize % SEQUENCE_BUFFER_SIZE] == Some(sequence)
+    }
+
+    /// Checks if a sequence number has neither been seen, nor is older
+    /// than the window of remembered sequence numbers.
+    fn is_new(&self, sequence: u16) -> bool {
+        match self.entries[sequence as usize % SEQUENCE_BUFFER_SIZE] {
+            Some(existing) => sequence_greater_than(sequence, existing),
+            None => true,
+        }
+    }
+}
+
+/// Compares two sequence numbers, taking wrap-around into account.
+fn sequence_greater_than(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Channel, Config, Delivery, SEQUENCE_BUFFER_SIZE};
+    use crate::wire::{dgram::{self, sim}, middleware};
+    use crate::Settings;
+    use std::time::{Duration, Instant};
+
+    type TestChannel = Channel<u32, middleware::pipeline::Default>;
+
+    fn channel() -> TestChannel {
+        Channel::new(dgram::Pipeline::new(middleware::pipeline::default(), Settings::default()),
+                     Config::default())
+    }
+
+    /// Sends packets over a lossy link in both directions and returns
+    /// what the receiver saw.
+    fn exchange(delivery: Delivery, conditions: sim::Conditions, count: u32) -> (Vec<u32>, TestChannel) {
+        let (mut sender, mut receiver) = (channel(), channel());
+        let mut forward = sim::Link::new(conditions.clone(), 1);
+        let mut backward = sim::Link::new(conditions, 2);
+
+        let start = Instant::now();
+        let mut received = Vec::new();
+
+        for tick in 0..2000u64 {
+            let now = start + Duration::from_millis(tick * 10);
+
+            if (tick as u32) < count {
+                forward.send(&sender.send(&(tick as u32), delivery, now).unwrap(), now);
+            }
+
+            for datagram in sender.update(now).unwrap() { forward.send(&datagram, now); }
+            for datagram in receiver.update(now).unwrap() { backward.send(&datagram, now); }
+
+            while let Some(datagram) = forward.receive(now) {
+                received.extend(receiver.receive(&datagram, now).unwrap());
+            }
+            while let Some(datagram) = backward.receive(now) {
+                sender.receive(&datagram, now).unwrap();
+            }
+        }
+
+        (received, sender)
+    }
+
+    fn lossy() -> sim::Conditions {
+        sim::Conditions {
+            latency: Duration::from_millis(30),
+            jitter: Duration::from_millis(40),
+            loss: 0.3,
+            duplication: 0.2,
+            ..sim::Conditions::default()
+        }
+    }
+
+    #[test]
+    fn reliable_ordered_delivers_everything_in_order() {
+        let (received, sender) = exchange(Delivery::ReliableOrdered, lossy(), 200);
+
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+        assert_eq!(sender.unacknowledged(), 0);
+    }
+
+    #[test]
+    fn reliable_unordered_delivers_everything_once() {
+        let (mut received, sender) = exchange(Delivery::ReliableUnordered, lossy(), 200);
+
+        received.sort();
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+        assert_eq!(sender.unacknowledged(), 0);
+    }
+
+    #[test]
+    fn unreliable_packets_are_not_retransmitted() {
+        let (received, sender) = exchange(Delivery::Unreliable, sim::Conditions { loss: 1.0, ..sim::Conditions::default() }, 20);
+
+        assert!(received.is_empty());
+        assert_eq!(sender.unacknowledged(), 0);
+    }
+
+    #[test]
+    fn duplicate_datagrams_are_suppressed() {
+        let (mut sender, mut receiver) = (channel(), channel());
+        let now = Instant::now();
+
+        let datagram = sender.send(&7, Delivery::ReliableUnordered, now).unwrap();
+
+        assert_eq!(receiver.receive(&datagram, now).unwrap(), vec![7]);
+        assert_eq!(receiver.receive(&datagram, now).unwrap(), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn unacknowledged_packets_are_retransmitted_after_timeout() {
+        let mut sender = channel();
+        let now = Instant::now();
+
+        sender.send(&7, Delivery::ReliableOrdered, now).unwrap();
+
+        assert!(sender.update(now + Duration::from_millis(99)).unwrap().is_empty());
+        assert_eq!(sender.update(now + Duration::from_millis(100)).unwrap().len(), 1);
+        assert_eq!(sender.unacknowledged(), 1);
+    }
+
+    #[test]
+    fn out_of_order_packets_are_held_back() {
+        let (mut sender, mut receiver) = (channel(), channel());
+        let now = Instant::now();
+
+        let first = sender.send(&1, Delivery::ReliableOrdered, now).unwrap();
+        let second = sender.send(&2, Delivery::ReliableOrdered, now).unwrap();
+
+        assert_eq!(receiver.receive(&second, now).unwrap(), Vec::<u32>::new());
+        assert_eq!(receiver.receive(&first, now).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn ordered_packets_too_far_ahead_are_not_acknowledged() {
+        let (mut sender, mut receiver) = (channel(), channel());
+        let now = Instant::now();
+
+        let datagrams: Vec<_> = (0..=SEQUENCE_BUFFER_SIZE as u32)
+            .map(|i| sender.send(&i, Delivery::ReliableOrdered, now).unwrap())
+            .collect();
+
+        assert_eq!(receiver.receive(datagrams.last().unwrap(), now).unwrap(), Vec::<u32>::new());
+        assert!(receiver.update(now).unwrap().is_empty());
+
+        for datagram in &datagrams[..SEQUENCE_BUFFER_SIZE] {
+            receiver.receive(datagram, now).unwrap();
+
+            for datagram in receiver.update(now).unwrap() {
+                sender.receive(&datagram, now).unwrap();
+            }
+        }
+
+        // Only the packet that was too far ahead is still outstanding.
+        assert_eq!(sender.unacknowledged(), 1);
+    }
+
+    #[test]
+    fn undecodable_payloads_are_not_acknowledged() {
+        let (mut sender, mut receiver) = (channel(), channel());
+        let now = Instant::now();
+
+        let datagram = sender.send(&7, Delivery::ReliableOrdered, now).unwrap();
+
+        assert!(receiver.receive(&datagram[..datagram.len() - 1], now).is_err());
+        assert!(receiver.update(now).unwrap().is_empty());
+        assert_eq!(receiver.receive(&datagram, now).unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn acknowledgements_measure_round_trip_time() {
+        let (mut sender, mut receiver) = (channel(), channel());
+        let now = Instant::now();
+
+        let datagram = sender.send(&1, Delivery::ReliableOrdered, now).unwrap();
+        receiver.receive(&datagram, now).unwrap();
+
+        for datagram in receiver.update(now).unwrap() {
+            sender.receive(&datagram, now + Duration::from_millis(40)).unwrap();
+        }
+
+        assert_eq!(sender.round_trip_time(), Some(Duration::from_millis(40)));
+    }
+}
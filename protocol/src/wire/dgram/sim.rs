@@ -0,0 +1,450 @@
+//! A simulated datagram link for testing behaviour on bad networks.
+//!
+//! A [Link] sits between two datagram [Pipeline]s and applies
+//! latency, jitter, loss, duplication, reordering and corruption to
+//! every datagram that passes through it. No sockets are involved,
+//! and all randomness is derived from a seed, so the same sequence
+//! of calls always produces the same sequence of deliveries.
+//!
+//! Time is never read from the system clock. Every call takes the
+//! current `Instant` from the caller, which makes it possible to
+//! step through time deterministically in unit tests.
+//!
+//! # Example
+//!
+//! ```
+//! use protocol::wire::{dgram::{self, sim}, middleware};
+//! use std::time::{Duration, Instant};
+//!
+//! let settings = protocol::Settings::default();
+//! let mut client = dgram::Pipeline::<u32, _>::new(middleware::pipeline::default(), settings.clone());
+//! let mut server = dgram::Pipeline::<u32, _>::new(middleware::pipeline::default(), settings);
+//!
+//! let mut link = sim::Link::new(sim::Conditions {
+//!     latency: Duration::from_millis(50),
+//!     ..sim::Conditions::default()
+//! }, 0xdeadbeef);
+//!
+//! let start = Instant::now();
+//! link.send_packet(&mut client, &1234, start).unwrap();
+//!
+//! // Nothing arrives until the latency has elapsed.
+//! assert_eq!(None, link.receive_packet(&mut server, start).unwrap());
+//! assert_eq!(Some(1234), link.receive_packet(&mut server, start + Duration::from_millis(50)).unwrap());
+//! ```
+
+use crate::{wire::dgram::Pipeline, wire::middleware, Error, Parcel};
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+/// The network conditions applied by a [Link].
+///
+/// All probabilities are in the range `0.0..=1.0`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conditions {
+    /// The base delay applied to every datagram.
+    pub latency: Duration,
+    /// The maximum random delay added on top of the latency.
+    pub jitter: Duration,
+    /// The probability that a datagram is dropped.
+    pub loss: f64,
+    /// The probability that a datagram is delivered twice.
+    pub duplication: f64,
+    /// The probability that a datagram is held back by `reorder_delay`,
+    /// letting datagrams sent after it overtake it.
+    pub reordering: f64,
+    /// The extra delay applied to reordered datagrams.
+    pub reorder_delay: Duration,
+    /// The probability that a single bit of a datagram is flipped.
+    pub corruption: f64,
+}
+
+/// Counters describing what a [Link] has done so far.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// The number of datagrams passed to `send`.
+    pub sent: u64,
+    /// The number of datagrams handed out by `receive`.
+    pub delivered: u64,
+    /// The number of datagrams that were dropped.
+    pub lost: u64,
+    /// The number of extra copies that were queued.
+    pub duplicated: u64,
+    /// The number of datagrams that were held back.
+    pub reordered: u64,
+    /// The number of datagrams that had a bit flipped.
+    pub corrupted: u64,
+}
+
+/// A one-way simulated datagram link.
+///
+/// Use two links to simulate a bidirectional connection.
+#[derive(Clone, Debug)]
+pub struct Link {
+    /// The conditions applied to datagrams sent from now on.
+    pub conditions: Conditions,
+    rng: Rng,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    next_id: u64,
+    statistics: Statistics,
+}
+
+/// A datagram that is waiting to be delivered.
+#[derive(Clone, Debug)]
+struct InFlight {
+    deliver_at: Instant,
+    /// Breaks ties between datagrams with the same delivery time
+    /// so that they are delivered in the order they were queued.
+    id: u64,
+    data: Vec<u8>,
+}
+
+/// A small deterministic pseudo-random number generator (SplitMix64).
+#[derive(Clone, Debug)]
+struct Rng(u64);
+
+impl Conditions {
+    /// A link that delivers every datagram immediately and intact.
+    pub const PERFECT: Conditions = Conditions {
+        latency: Duration::from_secs(0),
+        jitter: Duration::from_secs(0),
+        loss: 0.0,
+        duplication: 0.0,
+        reordering: 0.0,
+        reorder_delay: Duration::from_secs(0),
+        corruption: 0.0,
+    };
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Conditions::PERFECT
+    }
+}
+
+impl Link {
+    /// Creates a new link with the given conditions.
+    ///
+    /// Two links created with the same conditions and seed behave
+    /// identically when given the same sequence of calls.
+    pub fn new(conditions: Conditions, seed: u64) -> Self {
+        Link {
+            conditions,
+            rng: Rng(seed),
+            in_flight: BinaryHeap::new(),
+            next_id: 0,
+            statistics: Statistics::default(),
+        }
+    }
+
+    /// Sends a datagram into the link at time `now`.
+    pub fn send(&mut self, datagram: &[u8], now: Instant) {
+        self.statistics.sent += 1;
+
+        if self.rng.chance(self.conditions.loss) {
+            self.statistics.lost += 1;
+            return;
+        }
+
+        let copies = if self.rng.chance(self.conditions.duplication) {
+            self.statistics.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut data = datagram.to_owned();
+
+            if self.rng.chance(self.conditions.corruption) && !data.is_empty() {
+                let bit = self.rng.below(data.len() as u64 * 8);
+                data[(bit / 8) as usize] ^= 1 << (bit % 8);
+                self.statistics.corrupted += 1;
+            }
+
+            let mut delay = self.conditions.latency + self.rng.duration_up_to(self.conditions.jitter);
+
+            if self.rng.chance(self.conditions.reordering) {
+                delay += self.conditions.reorder_delay;
+                self.statistics.reordered += 1;
+            }
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            self.in_flight.push(Reverse(InFlight { deliver_at: now + delay, id, data }));
+        }
+    }
+
+    /// Receives the next datagram that has arrived by time `now`.
+    ///
+    /// Returns `None` if no datagram is ready yet.
+    pub fn receive(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.in_flight.peek() {
+            Some(Reverse(datagram)) if datagram.deliver_at <= now => (),
+            _ => return None,
+        }
+
+        self.statistics.delivered += 1;
+        self.in_flight.pop().map(|Reverse(datagram)| datagram.data)
+    }
+
+    /// Encodes a packet with a pipeline and sends it into the link.
+    pub fn send_packet<P, M>(&mut self,
+                             pipeline: &mut Pipeline<P, M>,
+                             packet: &P,
+                             now: Instant)
+        -> Result<(), Error>
+        where P: Parcel, M: middleware::Pipeline {
+        let mut datagram = Vec::new();
+        pipeline.send_into(&mut datagram, packet)?;
+
+        self.send(&datagram, now);
+        Ok(())
+    }
+
+    /// Receives the next datagram that has arrived by time `now`
+    /// and decodes it with a pipeline.
+    ///
+    /// Returns `Err` if the datagram could not be decoded, for
+    /// example because it was corrupted in transit.
+    pub fn receive_packet<P, M>(&mut self,
+                                pipeline: &mut Pipeline<P, M>,
+                                now: Instant)
+        -> Result<Option<P>, Error>
+        where P: Parcel, M: middleware::Pipeline {
+        match self.receive(now) {
+            Some(datagram) => pipeline.receive_from_slice(&datagram).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the time at which the next datagram will arrive, if any
+    /// datagrams are in flight.
+    pub fn next_delivery(&self) -> Option<Instant> {
+        self.in_flight.peek().map(|Reverse(datagram)| datagram.deliver_at)
+    }
+
+    /// Gets the number of datagrams that have not been received yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Gets the counters describing what the link has done so far.
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight { }
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.id).cmp(&(other.deliver_at, other.id))
+    }
+}
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Gets a uniformly distributed number in `0.0..1.0`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Gets a number in `0..bound`.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    /// Returns `true` with the given probability.
+    ///
+    /// Always consumes exactly one random number, even for probabilities
+    /// of zero and one, so that changing one probability does not shift
+    /// the outcomes of the others.
+    fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+
+    /// Gets a duration in `0..=max`.
+    ///
+    /// Like `chance`, this always consumes exactly one random number.
+    fn duration_up_to(&mut self, max: Duration) -> Duration {
+        let nanos = max.as_nanos() as u64;
+        Duration::from_nanos(self.below(nanos.saturating_add(1)))
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Conditions, Link};
+    use crate::wire::{dgram, middleware};
+    use crate::Settings;
+    use std::time::{Duration, Instant};
+
+    fn pipeline() -> dgram::Pipeline<u16, middleware::pipeline::Default> {
+        dgram::Pipeline::new(middleware::pipeline::default(), Settings::default())
+    }
+
+    fn deliver_all(link: &mut Link, now: Instant) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        while let Some(datagram) = link.receive(now) {
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+
+    #[test]
+    fn perfect_link_delivers_in_order() {
+        let mut link = Link::new(Conditions::PERFECT, 1);
+        let now = Instant::now();
+
+        for i in 0..10u8 {
+            link.send(&[i], now);
+        }
+
+        assert_eq!(deliver_all(&mut link, now), (0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn datagrams_are_held_until_latency_elapses() {
+        let mut link = Link::new(Conditions {
+            latency: Duration::from_millis(100),
+            ..Conditions::default()
+        }, 1);
+        let now = Instant::now();
+
+        link.send(&[1, 2, 3], now);
+
+        assert_eq!(link.receive(now + Duration::from_millis(99)), None);
+        assert_eq!(link.next_delivery(), Some(now + Duration::from_millis(100)));
+        assert_eq!(link.receive(now + Duration::from_millis(100)), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn same_seed_gives_same_deliveries() {
+        let conditions = Conditions {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(30),
+            loss: 0.2,
+            duplication: 0.1,
+            reordering: 0.1,
+            reorder_delay: Duration::from_millis(40),
+            corruption: 0.1,
+        };
+
+        let run = |seed| {
+            let mut link = Link::new(conditions.clone(), seed);
+            let now = Instant::now();
+
+            for i in 0..200u8 {
+                link.send(&[i, i, i, i], now + Duration::from_millis(i as u64));
+            }
+            (deliver_all(&mut link, now + Duration::from_secs(10)), *link.statistics())
+        };
+
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn changing_one_condition_does_not_shift_the_others() {
+        let lost = |jitter| {
+            let mut link = Link::new(Conditions { loss: 0.5, jitter, ..Conditions::default() }, 9);
+            let now = Instant::now();
+
+            for i in 0..100u8 {
+                link.send(&[i], now);
+            }
+
+            let delivered: Vec<u8> = deliver_all(&mut link, now + Duration::from_secs(1)).iter().map(|d| d[0]).collect();
+            (0..100u8).filter(|i| !delivered.contains(i)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(lost(Duration::from_secs(0)), lost(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn total_loss_drops_everything() {
+        let mut link = Link::new(Conditions { loss: 1.0, ..Conditions::default() }, 7);
+        let now = Instant::now();
+
+        for _ in 0..50 {
+            link.send(&[0], now);
+        }
+
+        assert!(deliver_all(&mut link, now).is_empty());
+        assert_eq!(link.statistics().lost, 50);
+    }
+
+    #[test]
+    fn total_duplication_delivers_twice() {
+        let mut link = Link::new(Conditions { duplication: 1.0, ..Conditions::default() }, 7);
+        let now = Instant::now();
+
+        link.send(&[9], now);
+
+        assert_eq!(deliver_all(&mut link, now), vec![vec![9], vec![9]]);
+    }
+
+    #[test]
+    fn corruption_flips_exactly_one_bit() {
+        let mut link = Link::new(Conditions { corruption: 1.0, ..Conditions::default() }, 7);
+        let now = Instant::now();
+
+        link.send(&[0; 16], now);
+
+        let received = link.receive(now).unwrap();
+        assert_eq!(received.iter().map(|b| b.count_ones()).sum::<u32>(), 1);
+    }
+
+    #[test]
+    fn reordered_datagrams_are_overtaken() {
+        let mut link = Link::new(Conditions {
+            reordering: 1.0,
+            reorder_delay: Duration::from_millis(10),
+            ..Conditions::default()
+        }, 7);
+        let now = Instant::now();
+
+        link.send(&[1], now);
+        link.conditions.reordering = 0.0;
+        link.send(&[2], now);
+
+        assert_eq!(deliver_all(&mut link, now + Duration::from_millis(10)), vec![vec![2], vec![1]]);
+    }
+
+    #[test]
+    fn packets_pass_between_pipelines() {
+        let (mut sender, mut receiver) = (pipeline(), pipeline());
+        let mut link = Link::new(Conditions::PERFECT, 3);
+        let now = Instant::now();
+
+        link.send_packet(&mut sender, &0xbeef, now).unwrap();
+
+        assert_eq!(link.receive_packet(&mut receiver, now).unwrap(), Some(0xbeef));
+        assert_eq!(link.receive_packet(&mut receiver, now).unwrap(), None);
+    }
+}
@@ -0,0 +1,500 @@
+//! Datagram communication over Unix domain sockets.
+//!
+//! Unix datagram sockets are useful for local IPC. Unlike UDP, a peer
+//! does not need to be bound to an address, in which case replies can
+//! only be sent over a connected socket. Unix sockets can also carry
+//! open file descriptors alongside the data (`SCM_RIGHTS`).
+//!
+//! # Example
+//!
+//! ```
+//! use protocol::wire::{dgram::unix, middleware};
+//!
+//! let settings = protocol::Settings::default();
+//! let (mut a, mut b) = unix::Endpoint::<String, _>::pair(|_| middleware::pipeline::default(), settings).unwrap();
+//!
+//! a.send(&"hello".to_owned()).unwrap();
+//! assert_eq!(b.receive().unwrap(), (unix::Address::Unnamed, "hello".to_owned()));
+//! ```
+
+use super::endpoint::{peer_pipeline, NewPeer, DEFAULT_MAX_PEERS, MAX_DATAGRAM_SIZE};
+use crate::{wire::dgram::Pipeline, wire::middleware, Error, ErrorKind, Parcel, Settings};
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{self, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::{io, mem, ptr};
+
+/// The most file descriptors that can be sent with a single datagram.
+///
+/// This is the limit imposed by Linux (`SCM_MAX_FD`).
+pub const MAX_FDS: usize = 253;
+
+/// The address of a Unix datagram socket.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// A socket bound to a path in the filesystem.
+    Path(PathBuf),
+    /// A socket that is not bound to a path, such as either end of
+    /// `UnixDatagram::pair`.
+    ///
+    /// Unnamed peers cannot be told apart from each other, so they all
+    /// share the same middleware state, and can only be replied to over
+    /// a connected socket. Linux abstract socket addresses are treated
+    /// as unnamed.
+    Unnamed,
+}
+
+/// A datagram endpoint that owns a Unix datagram socket.
+///
+/// Like the UDP `Endpoint`, every peer gets its own middleware
+/// pipeline, created by a closure the first time the peer is seen, and
+/// the number of peers is limited.
+#[derive(Debug)]
+pub struct Endpoint<P: Parcel, M: middleware::Pipeline>
+{
+    pub socket: UnixDatagram,
+    pub settings: Settings,
+
+    new_peer: NewPeer<Address, M>,
+    peers: HashMap<Address, Pipeline<P, M>>,
+    max_peers: usize,
+    send_buffer: Vec<u8>,
+    receive_buffer: Vec<u8>,
+}
+
+impl<P,M> Endpoint<P,M>
+    where P: Parcel, M: middleware::Pipeline
+{
+    /// Creates a new endpoint from a socket.
+    ///
+    /// `new_peer_middleware` is called with the address of every new peer.
+    pub fn new<F>(socket: UnixDatagram,
+                  new_peer_middleware: F,
+                  settings: Settings) -> Self
+        where F: FnMut(&Address) -> M + Send + 'static {
+        Endpoint {
+            socket,
+            settings,
+            new_peer: NewPeer::new(new_peer_middleware),
+            peers: HashMap::new(),
+            max_peers: DEFAULT_MAX_PEERS,
+            send_buffer: Vec::new(),
+            receive_buffer: vec![0; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    /// Creates a new endpoint bound to a path.
+    pub fn bind<A,F>(path: A,
+                     new_peer_middleware: F,
+                     settings: Settings) -> Result<Self, Error>
+        where A: AsRef<Path>, F: FnMut(&Address) -> M + Send + 'static {
+        Ok(Endpoint::new(UnixDatagram::bind(path)?, new_peer_middleware, settings))
+    }
+
+    /// Creates a new endpoint that is not bound to a path.
+    pub fn unbound<F>(new_peer_middleware: F,
+                      settings: Settings) -> Result<Self, Error>
+        where F: FnMut(&Address) -> M + Send + 'static {
+        Ok(Endpoint::new(UnixDatagram::unbound()?, new_peer_middleware, settings))
+    }
+
+    /// Creates a pair of endpoints connected to each other.
+    ///
+    /// Each endpoint gets its own copy of `new_peer_middleware`.
+    pub fn pair<F>(new_peer_middleware: F,
+                   settings: Settings) -> Result<(Self, Self), Error>
+        where F: FnMut(&Address) -> M + Clone + Send + 'static {
+        let (a, b) = UnixDatagram::pair()?;
+
+        Ok((Endpoint::new(a, new_peer_middleware.clone(), settings.clone()),
+            Endpoint::new(b, new_peer_middleware, settings)))
+    }
+
+    /// Sends a packet to the peer the socket is connected to.
+    pub fn send(&mut self, packet: &P) -> Result<(), Error> {
+        self.send_with_fds(packet, &[], None)
+    }
+
+    /// Sends a packet to the socket bound to a path.
+    pub fn send_to(&mut self,
+                   packet: &P,
+                   path: &Path)
+        -> Result<(), Error> {
+        self.send_with_fds(packet, &[], Some(path))
+    }
+
+    /// Sends a packet along with some open file descriptors.
+    ///
+    /// The packet is sent to `path`, or to the connected peer if no path
+    /// is given. The receiver gets its own duplicates of the descriptors,
+    /// so they remain open on this side.
+    pub fn send_with_fds(&mut self,
+                         packet: &P,
+                         fds: &[RawFd],
+                         path: Option<&Path>)
+        -> Result<(), Error> {
+        let address = match path {
+            Some(path) => Address::Path(path.to_owned()),
+            None => Address::from(&self.socket.peer_addr()?),
+        };
+
+        self.send_buffer.clear();
+        let pipeline = peer_pipeline(&mut self.peers, &mut self.new_peer, self.max_peers, &self.settings, address)?;
+        pipeline.send_into(&mut self.send_buffer, packet)?;
+
+        send_message(&self.socket, &self.send_buffer, fds, path)?;
+        Ok(())
+    }
+
+    /// Receives a packet from any peer.
+    ///
+    /// Blocks unless the socket is in non-blocking mode. Any file
+    /// descriptors sent along with the packet are closed.
+    pub fn receive(&mut self) -> Result<(Address, P), Error> {
+        let (address, packet, _) = self.receive_with_fds()?;
+        Ok((address, packet))
+    }
+
+    /// Attempts to receive a packet from any peer.
+    ///
+    /// Returns `Ok(None)` if the socket is in non-blocking mode and no
+    /// datagram is available.
+    pub fn try_receive(&mut self) -> Result<Option<(Address, P)>, Error> {
+        match self.receive() {
+            Ok(received) => Ok(Some(received)),
+            Err(Error(ErrorKind::Io(ref e), _)) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Receives a packet from any peer, along with any file descriptors
+    /// that were sent with it.
+    pub fn receive_with_fds(&mut self) -> Result<(Address, P, Vec<OwnedFd>), Error> {
+        let (bytes_read, address, fds) = receive_message(&self.socket, &mut self.receive_buffer)?;
+
+        let datagram = &self.receive_buffer[..bytes_read];
+        let pipeline = peer_pipeline(&mut self.peers, &mut self.new_peer, self.max_peers, &self.settings, address.clone())?;
+        let packet = pipeline.receive_from_slice(datagram)?;
+
+        Ok((address, packet, fds))
+    }
+
+    /// Sets the size of the largest datagram that can be received.
+    ///
+    /// Defaults to 65535 bytes. Larger datagrams fail to be received.
+    pub fn set_max_datagram_size(&mut self, size: usize) {
+        self.receive_buffer.resize(size, 0);
+    }
+
+    /// Gets the middleware used for a peer, if the peer is known.
+    pub fn peer_middleware(&self, address: &Address) -> Option<&M> {
+        self.peers.get(address).map(|pipeline| &pipeline.middleware)
+    }
+
+    /// Gets the middleware used for a peer.
+    ///
+    /// The peer's middleware is created if it has not been seen yet,
+    /// which fails if the endpoint already knows as many peers as it may.
+    pub fn peer_middleware_mut(&mut self, address: Address) -> Result<&mut M, Error> {
+        Ok(&mut peer_pipeline(&mut self.peers, &mut self.new_peer, self.max_peers, &self.settings, address)?.middleware)
+    }
+
+    /// Sets the largest number of peers to keep middleware for.
+    ///
+    /// Defaults to `DEFAULT_MAX_PEERS`. Peers that are already known are
+    /// kept even if there are more of them than the new limit.
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.max_peers = max_peers;
+    }
+
+    /// Gets the largest number of peers to keep middleware for.
+    pub fn max_peers(&self) -> usize {
+        self.max_peers
+    }
+
+    /// Forgets a peer and its middleware state.
+    pub fn remove_peer(&mut self, address: &Address) -> Option<M> {
+        self.peers.remove(address).map(|pipeline| pipeline.middleware)
+    }
+
+    /// Gets the addresses of all known peers.
+    pub fn peers(&self) -> impl Iterator<Item=&Address> {
+        self.peers.keys()
+    }
+
+    pub fn into_inner(self) -> UnixDatagram { self.socket }
+}
+
+impl<'a> From<&'a net::SocketAddr> for Address {
+    fn from(address: &'a net::SocketAddr) -> Self {
+        match address.as_pathname() {
+            Some(path) => Address::Path(path.to_owned()),
+            None => Address::Unnamed,
+        }
+    }
+}
+
+/// Gets the offset of `sun_path` within `sockaddr_un`.
+fn sun_path_offset(address: &libc::sockaddr_un) -> usize {
+    address.sun_path.as_ptr() as usize - address as *const _ as usize
+}
+
+/// Sends a datagram with `sendmsg`, attaching file descriptors if any.
+fn send_message(socket: &UnixDatagram,
+                data: &[u8],
+                fds: &[RawFd],
+                path: Option<&Path>)
+    -> io::Result<()> {
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many file descriptors"));
+    }
+
+    // SAFETY: all-zero is a valid `sockaddr_un` and `msghdr`.
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+
+    if let Some(path) = path {
+        let bytes = path.as_os_str().as_bytes();
+
+        // Leave room for the NUL terminator.
+        if bytes.len() >= address.sun_path.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "socket path is too long"));
+        }
+
+        address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dst, &src) in address.sun_path.iter_mut().zip(bytes) {
+            *dst = src as libc::c_char;
+        }
+
+        message.msg_name = &mut address as *mut libc::sockaddr_un as *mut libc::c_void;
+        message.msg_namelen = (sun_path_offset(&address) + bytes.len() + 1) as libc::socklen_t;
+    }
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+
+    // Use u64 elements so the control buffer is suitably aligned for `cmsghdr`.
+    let fds_size = mem::size_of_val(fds);
+    // SAFETY: `CMSG_SPACE` only performs arithmetic.
+    let control_size = unsafe { libc::CMSG_SPACE(fds_size as u32) } as usize;
+    let mut control = vec![0u64; control_size.div_ceil(mem::size_of::<u64>())];
+
+    if !fds.is_empty() {
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control_size as _;
+
+        // SAFETY: the control buffer is large enough for a single header
+        // carrying `fds.len()` descriptors, so the first header is not null.
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(fds_size as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, libc::CMSG_DATA(header), fds_size);
+        }
+    }
+
+    // SAFETY: every pointer in `message` refers to a live local.
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &message, 0) };
+
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Receives a datagram with `recvmsg`, collecting any file descriptors.
+fn receive_message(socket: &UnixDatagram,
+                   buffer: &mut [u8])
+    -> io::Result<(usize, Address, Vec<OwnedFd>)> {
+    // SAFETY: all-zero is a valid `sockaddr_un` and `msghdr`.
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+
+    // SAFETY: `CMSG_SPACE` only performs arithmetic.
+    let control_size = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) } as usize;
+    let mut control = vec![0u64; control_size.div_ceil(mem::size_of::<u64>())];
+
+    message.msg_name = &mut address as *mut libc::sockaddr_un as *mut libc::c_void;
+    message.msg_namelen = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = control_size as _;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = 0;
+
+    // SAFETY: every pointer in `message` refers to a live local.
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, flags) };
+
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Take ownership of the descriptors first, so that they are closed
+    // if anything below fails.
+    let mut fds = Vec::new();
+
+    // SAFETY: the kernel filled in `message.msg_control` with well-formed
+    // headers, and `SCM_RIGHTS` payloads are arrays of descriptors.
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(header);
+                let length = (*header).cmsg_len as usize - (data as usize - header as usize);
+
+                for i in 0..length / mem::size_of::<RawFd>() {
+                    let fd = ptr::read_unaligned((data as *const RawFd).add(i));
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+
+    if message.msg_flags & libc::MSG_TRUNC != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "datagram larger than the receive buffer"));
+    }
+    if message.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "too many file descriptors received"));
+    }
+
+    let path_length = (message.msg_namelen as usize).saturating_sub(sun_path_offset(&address));
+    let path: Vec<u8> = address.sun_path[..path_length].iter()
+        .map(|&c| c as u8)
+        .take_while(|&c| c != 0)
+        .collect();
+
+    let address = if path.is_empty() {
+        Address::Unnamed
+    } else {
+        Address::Path(PathBuf::from(OsStr::from_bytes(&path)))
+    };
+
+    Ok((received as usize, address, fds))
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Address, Endpoint};
+    use crate::wire::middleware::{self, rotate_bytes::RotateBytes};
+    use crate::Settings;
+
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    crate::define_middleware_pipeline!(Rotating {
+        rotate: RotateBytes
+    });
+
+    /// Gets a socket path that is unique to this test run.
+    fn socket_path() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!("protocol-unix-test-{}-{}.sock",
+                                                     std::process::id(),
+                                                     COUNTER.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn paired_endpoints_talk_in_both_directions() {
+        let (mut a, mut b) = Endpoint::<String, _>::pair(|_| middleware::pipeline::default(), Settings::default()).unwrap();
+
+        a.send(&"ping".to_owned()).unwrap();
+        assert_eq!(b.receive().unwrap(), (Address::Unnamed, "ping".to_owned()));
+
+        b.send(&"pong".to_owned()).unwrap();
+        assert_eq!(a.receive().unwrap(), (Address::Unnamed, "pong".to_owned()));
+    }
+
+    #[test]
+    fn bound_endpoints_see_the_sender_path() {
+        let (server_path, client_path) = (socket_path(), socket_path());
+        let mut server = Endpoint::<u32, _>::bind(&server_path, |_| middleware::pipeline::default(), Settings::default()).unwrap();
+        let mut client = Endpoint::<u32, _>::bind(&client_path, |_| middleware::pipeline::default(), Settings::default()).unwrap();
+
+        client.send_to(&1, &server_path).unwrap();
+        let (from, packet) = server.receive().unwrap();
+        assert_eq!((from.clone(), packet), (Address::Path(client_path.clone()), 1));
+
+        match from {
+            Address::Path(path) => server.send_to(&2, &path).unwrap(),
+            Address::Unnamed => unreachable!(),
+        }
+        assert_eq!(client.receive().unwrap(), (Address::Path(server_path.clone()), 2));
+
+        fs::remove_file(server_path).unwrap();
+        fs::remove_file(client_path).unwrap();
+    }
+
+    #[test]
+    fn unbound_senders_are_unnamed() {
+        let server_path = socket_path();
+        let mut server = Endpoint::<u32, _>::bind(&server_path, |_| middleware::pipeline::default(), Settings::default()).unwrap();
+        let mut client = Endpoint::<u32, _>::unbound(|_| middleware::pipeline::default(), Settings::default()).unwrap();
+
+        client.send_to(&7, &server_path).unwrap();
+        assert_eq!(server.receive().unwrap(), (Address::Unnamed, 7));
+
+        fs::remove_file(server_path).unwrap();
+    }
+
+    #[test]
+    fn middleware_is_kept_per_peer() {
+        let (mut a, mut b) = Endpoint::<String, _>::pair(|_| Rotating { rotate: RotateBytes::ROT13 }, Settings::default()).unwrap();
+
+        a.send(&"secret".to_owned()).unwrap();
+        assert_eq!(b.receive().unwrap().1, "secret");
+        assert_eq!(b.peer_middleware(&Address::Unnamed).unwrap().rotate.amount, 13);
+    }
+
+    #[test]
+    fn file_descriptors_are_passed_with_packets() {
+        let (mut a, mut b) = Endpoint::<String, _>::pair(|_| middleware::pipeline::default(), Settings::default()).unwrap();
+
+        let path = socket_path().with_extension("txt");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.write_all(b"shared contents").unwrap();
+
+        a.send_with_fds(&"here is a file".to_owned(), &[file.as_raw_fd()], None).unwrap();
+
+        let (_, packet, fds) = b.receive_with_fds().unwrap();
+        assert_eq!(packet, "here is a file");
+        assert_eq!(fds.len(), 1);
+
+        let mut received = File::from(fds.into_iter().next().unwrap());
+        received.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut contents = String::new();
+        received.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "shared contents");
+
+        fs::remove_file(path).unwrap();
+    }
+}
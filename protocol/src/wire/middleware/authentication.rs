@@ -0,0 +1,252 @@
+//! A middleware for authenticating all transmitted data.
+//!
+//! Requires the `middleware-authentication` crate feature to be enabled.
+//!
+//! An HMAC-SHA256 tag is appended to every frame. The data itself is
+//! left readable, so this is for links that need integrity but not
+//! confidentiality.
+//!
+//! With replay protection enabled, every frame is also prefixed by a
+//! sequence number that is covered by the tag. Frames with a sequence
+//! number that has already been received are rejected. The same key
+//! should not be used for both directions of a connection in that case,
+//! or frames could be reflected back to their sender.
+
+use crate::{wire, wire::middleware::{replay::ReplayWindow, Frame}, Error, ErrorKind};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
+
+/// The size of an untruncated HMAC-SHA256 tag.
+pub const MAX_TAG_SIZE: usize = 32;
+/// The smallest tag size that can be configured.
+pub const MIN_TAG_SIZE: usize = 4;
+/// The size of the sequence number prepended with replay protection.
+pub const SEQUENCE_SIZE: usize = 8;
+
+/// HMAC-SHA256 authentication middleware.
+#[derive(Clone)]
+pub struct Authentication
+{
+    mac: Hmac<Sha256>,
+    tag_size: usize,
+    replay: Option<Replay>,
+}
+
+/// The sequence numbers used for replay protection.
+#[derive(Clone, Debug)]
+struct Replay
+{
+    next_sequence: u64,
+    window: ReplayWindow,
+}
+
+impl Authentication
+{
+    /// Creates a new authentication middleware.
+    ///
+    /// Tags are truncated to `tag_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the tag size is not between `MIN_TAG_SIZE` and
+    /// `MAX_TAG_SIZE`.
+    pub fn new(key: &[u8],
+               tag_size: usize) -> Self {
+        assert!((MIN_TAG_SIZE..=MAX_TAG_SIZE).contains(&tag_size),
+                "HMAC tag size must be between {} and {} bytes", MIN_TAG_SIZE, MAX_TAG_SIZE);
+
+        Authentication {
+            mac: Hmac::new_from_slice(key).expect("HMAC accepts keys of any size"),
+            tag_size,
+            replay: None,
+        }
+    }
+
+    /// Creates a new authentication middleware with replay protection.
+    pub fn with_replay_protection(key: &[u8],
+                                  tag_size: usize) -> Self {
+        Authentication {
+            replay: Some(Replay { next_sequence: 0, window: ReplayWindow::new() }),
+            ..Authentication::new(key, tag_size)
+        }
+    }
+
+    /// Gets the size of the tags in bytes.
+    pub fn tag_size(&self) -> usize { self.tag_size }
+
+    /// Checks if replayed frames are rejected.
+    pub fn is_replay_protected(&self) -> bool { self.replay.is_some() }
+
+    fn overhead(&self) -> usize {
+        self.tag_size + if self.replay.is_some() { SEQUENCE_SIZE } else { 0 }
+    }
+}
+
+impl wire::Middleware for Authentication
+{
+    fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.encode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.decode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn encode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        let mut mac = self.mac.clone();
+
+        if let Some(ref mut replay) = self.replay {
+            let mut sequence = [0; SEQUENCE_SIZE];
+            BigEndian::write_u64(&mut sequence, replay.next_sequence);
+            replay.next_sequence += 1;
+
+            frame.prepend(&sequence);
+        }
+
+        mac.update(&frame[..]);
+        let tag = mac.finalize().into_bytes();
+        frame.extend_from_slice(&tag[..self.tag_size]);
+
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        if frame.len() < self.overhead() {
+            return Err(ErrorKind::MacMismatch.into());
+        }
+
+        let tag_start = frame.len() - self.tag_size;
+        let sequence = match self.replay {
+            Some(ref replay) => {
+                let sequence = BigEndian::read_u64(&frame[..SEQUENCE_SIZE]);
+                if !replay.window.check(sequence) {
+                    return Err(ErrorKind::ReplayedFrame(sequence).into());
+                }
+                Some(sequence)
+            },
+            None => None,
+        };
+
+        let mut mac = self.mac.clone();
+        mac.update(&frame[..tag_start]);
+        mac.verify_truncated_left(&frame[tag_start..]).map_err(|_| ErrorKind::MacMismatch)?;
+
+        frame.truncate(tag_start);
+        if let (Some(replay), Some(sequence)) = (self.replay.as_mut(), sequence) {
+            replay.window.accept(sequence);
+            frame.advance(SEQUENCE_SIZE);
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Authentication
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // Never print key material.
+        fmt.debug_struct("Authentication")
+           .field("tag_size", &self.tag_size)
+           .field("replay", &self.replay)
+           .finish()
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use crate::wire::Middleware;
+
+    const KEY: &[u8] = b"a very secret key";
+
+    #[test]
+    fn tags_are_appended_and_verified() {
+        let (mut sender, mut receiver) = (Authentication::new(KEY, MAX_TAG_SIZE), Authentication::new(KEY, MAX_TAG_SIZE));
+
+        let frame = sender.encode_data(b"hello".to_vec()).unwrap();
+        assert_eq!(frame.len(), 5 + MAX_TAG_SIZE);
+        assert_eq!(&frame[..5], b"hello");
+        assert_eq!(receiver.decode_data(frame).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn tags_can_be_truncated() {
+        let mut full = Authentication::new(KEY, MAX_TAG_SIZE);
+        let mut truncated = Authentication::new(KEY, 12);
+
+        let full_frame = full.encode_data(vec![1, 2, 3]).unwrap();
+        let truncated_frame = truncated.encode_data(vec![1, 2, 3]).unwrap();
+
+        assert_eq!(truncated_frame.len(), 3 + 12);
+        assert_eq!(truncated_frame[..], full_frame[..3 + 12]);
+        assert_eq!(truncated.decode_data(truncated_frame).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn tags_cannot_be_truncated_too_far() {
+        Authentication::new(KEY, MIN_TAG_SIZE - 1);
+    }
+
+    #[test]
+    fn tampered_frames_are_rejected() {
+        let mut sender = Authentication::new(KEY, 16);
+        let mut receiver = Authentication::new(KEY, 16);
+        let mut other_key = Authentication::new(b"another key", 16);
+
+        let frame = sender.encode_data(vec![1, 2, 3]).unwrap();
+
+        for index in 0..frame.len() {
+            let mut tampered = frame.clone();
+            tampered[index] ^= 1;
+
+            match receiver.decode_data(tampered) {
+                Err(Error(ErrorKind::MacMismatch, _)) => (),
+                result => panic!("tampered frame was accepted: {:?}", result),
+            }
+        }
+
+        assert!(other_key.decode_data(frame.clone()).is_err());
+        assert!(receiver.decode_data(frame[..10].to_vec()).is_err());
+        assert!(receiver.decode_data(frame).is_ok());
+    }
+
+    #[test]
+    fn replayed_frames_are_rejected() {
+        let mut sender = Authentication::with_replay_protection(KEY, 16);
+        let mut receiver = Authentication::with_replay_protection(KEY, 16);
+
+        let first = sender.encode_data(vec![1]).unwrap();
+        let second = sender.encode_data(vec![2]).unwrap();
+        assert_eq!(first.len(), SEQUENCE_SIZE + 1 + 16);
+
+        assert_eq!(receiver.decode_data(second.clone()).unwrap(), vec![2]);
+        assert_eq!(receiver.decode_data(first.clone()).unwrap(), vec![1]);
+
+        match receiver.decode_data(first) {
+            Err(Error(ErrorKind::ReplayedFrame(0), _)) => (),
+            result => panic!("replayed frame was accepted: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn forged_sequence_numbers_do_not_move_the_window() {
+        let mut sender = Authentication::with_replay_protection(KEY, 16);
+        let mut receiver = Authentication::with_replay_protection(KEY, 16);
+
+        let frame = sender.encode_data(vec![1]).unwrap();
+        let mut forged = frame.clone();
+        forged[0] = 0xff;
+
+        assert!(receiver.decode_data(forged).is_err());
+        assert_eq!(receiver.decode_data(frame).unwrap(), vec![1]);
+    }
+}
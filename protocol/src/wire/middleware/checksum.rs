@@ -0,0 +1,234 @@
+//! A middleware for detecting corrupted data.
+//!
+//! Requires the `middleware-checksum` crate feature to be enabled.
+//!
+//! A checksum of the data is appended to every frame in big endian
+//! byte order. Frames that fail verification are reported as
+//! `ErrorKind::ChecksumMismatch`. By the time middleware sees a frame
+//! it has already been taken out of the transport, so the caller can
+//! drop it and carry on receiving.
+//!
+//! Checksums only guard against accidental corruption. Use the
+//! authentication middleware to guard against tampering.
+
+use crate::{wire, wire::middleware::Frame, Error, ErrorKind};
+use crc::Crc;
+
+use byteorder::{BigEndian, ByteOrder};
+
+static CRC_16_ARC: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_ARC);
+static CRC_16_CCITT_FALSE: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+static CRC_16_KERMIT: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_KERMIT);
+static CRC_16_MODBUS: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_MODBUS);
+static CRC_16_X25: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+static CRC_16_XMODEM: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_XMODEM);
+static CRC_32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+static CRC_32C: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+/// Defines a checksum algorithm.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm
+{
+    /// A 16-bit cyclic redundancy check.
+    Crc16(Crc16),
+    /// The 32-bit cyclic redundancy check used by Ethernet, zlib and PNG.
+    Crc32,
+    /// The 32-bit cyclic redundancy check with the Castagnoli polynomial,
+    /// as used by iSCSI and SCTP.
+    Crc32c,
+    /// The Adler-32 checksum used by zlib.
+    ///
+    /// <https://en.wikipedia.org/wiki/Adler-32>
+    Adler32,
+}
+
+/// Defines a 16-bit cyclic redundancy check variant.
+///
+/// <https://reveng.sourceforge.io/crc-catalogue/16.htm>
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Crc16
+{
+    /// CRC-16/ARC, also known as CRC-16/IBM and CRC-16/LHA.
+    Arc,
+    /// CRC-16/CCITT-FALSE, also known as CRC-16/IBM-3740.
+    CcittFalse,
+    /// CRC-16/KERMIT, also known as CRC-16/CCITT.
+    Kermit,
+    /// CRC-16/MODBUS.
+    Modbus,
+    /// CRC-16/X-25, also known as CRC-16/IBM-SDLC, as used by HDLC.
+    X25,
+    /// CRC-16/XMODEM, also known as CRC-16/ACORN.
+    Xmodem,
+}
+
+/// Checksum middleware.
+#[derive(Clone, Debug)]
+pub enum Checksum
+{
+    /// No checksum should be appended to or verified on the data.
+    Disabled,
+    /// A checksum should be appended to and verified on the data.
+    Enabled(Algorithm),
+}
+
+impl Algorithm
+{
+    /// Gets the size of the checksum in bytes.
+    pub fn size(&self) -> usize {
+        match *self {
+            Algorithm::Crc16(..) => 2,
+            Algorithm::Crc32 | Algorithm::Crc32c | Algorithm::Adler32 => 4,
+        }
+    }
+
+    /// Calculates the checksum of some data.
+    pub fn checksum(&self, data: &[u8]) -> u32 {
+        match *self {
+            Algorithm::Crc16(variant) => variant.crc().checksum(data) as u32,
+            Algorithm::Crc32 => CRC_32.checksum(data),
+            Algorithm::Crc32c => CRC_32C.checksum(data),
+            Algorithm::Adler32 => adler::adler32_slice(data),
+        }
+    }
+}
+
+impl Crc16
+{
+    fn crc(&self) -> &'static Crc<u16> {
+        match *self {
+            Crc16::Arc => &CRC_16_ARC,
+            Crc16::CcittFalse => &CRC_16_CCITT_FALSE,
+            Crc16::Kermit => &CRC_16_KERMIT,
+            Crc16::Modbus => &CRC_16_MODBUS,
+            Crc16::X25 => &CRC_16_X25,
+            Crc16::Xmodem => &CRC_16_XMODEM,
+        }
+    }
+}
+
+impl wire::Middleware for Checksum
+{
+    fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.encode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.decode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn encode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        if let Checksum::Enabled(algorithm) = *self {
+            let checksum = algorithm.checksum(frame).to_be_bytes();
+
+            frame.extend_from_slice(&checksum[checksum.len() - algorithm.size()..]);
+        }
+
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        if let Checksum::Enabled(algorithm) = *self {
+            let size = algorithm.size();
+            // A frame too short to hold a checksum was truncated.
+            if frame.len() < size {
+                return Err(ErrorKind::ChecksumMismatch.into());
+            }
+
+            let body_size = frame.len() - size;
+            let expected = BigEndian::read_uint(&frame[body_size..], size) as u32;
+
+            if expected != algorithm.checksum(&frame[..body_size]) {
+                return Err(ErrorKind::ChecksumMismatch.into());
+            }
+
+            frame.truncate(body_size);
+        }
+
+        Ok(())
+    }
+
+    fn is_noop(&self) -> bool {
+        match *self {
+            Checksum::Disabled => true,
+            Checksum::Enabled(..) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use crate::wire::Middleware;
+
+    const ALGORITHMS: &[Algorithm] = &[
+        Algorithm::Crc16(Crc16::Arc),
+        Algorithm::Crc16(Crc16::CcittFalse),
+        Algorithm::Crc16(Crc16::Kermit),
+        Algorithm::Crc16(Crc16::Modbus),
+        Algorithm::Crc16(Crc16::X25),
+        Algorithm::Crc16(Crc16::Xmodem),
+        Algorithm::Crc32,
+        Algorithm::Crc32c,
+        Algorithm::Adler32,
+    ];
+
+    #[test]
+    fn checksums_match_the_check_values() {
+        let check_values = [0xbb3d, 0x29b1, 0x2189, 0x4b37, 0x906e, 0x31c3, 0xcbf43926, 0xe3069283, 0x091e01de];
+
+        for (algorithm, &check_value) in ALGORITHMS.iter().zip(check_values.iter()) {
+            assert_eq!(algorithm.checksum(b"123456789"), check_value, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn checksums_are_appended_and_verified() {
+        for &algorithm in ALGORITHMS {
+            let mut checksum = Checksum::Enabled(algorithm);
+
+            let frame = checksum.encode_data(b"123456789".to_vec()).unwrap();
+            assert_eq!(frame.len(), 9 + algorithm.size());
+            assert_eq!(BigEndian::read_uint(&frame[9..], algorithm.size()) as u32, algorithm.checksum(b"123456789"));
+            assert_eq!(checksum.decode_data(frame).unwrap(), b"123456789");
+        }
+    }
+
+    #[test]
+    fn corrupted_frames_are_rejected() {
+        for &algorithm in ALGORITHMS {
+            let mut checksum = Checksum::Enabled(algorithm);
+            let mut frame = checksum.encode_data(vec![1, 2, 3, 4]).unwrap();
+            frame[2] ^= 0x10;
+
+            match checksum.decode_data(frame) {
+                Err(Error(ErrorKind::ChecksumMismatch, _)) => (),
+                result => panic!("corrupted frame was accepted by {:?}: {:?}", algorithm, result),
+            }
+        }
+    }
+
+    #[test]
+    fn short_frames_are_rejected() {
+        let mut checksum = Checksum::Enabled(Algorithm::Crc32);
+
+        match checksum.decode_data(vec![1, 2, 3]) {
+            Err(Error(ErrorKind::ChecksumMismatch, _)) => (),
+            result => panic!("short frame was accepted: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn disabled_checksums_leave_data_alone() {
+        let mut checksum = Checksum::Disabled;
+
+        assert!(checksum.is_noop());
+        assert_eq!(checksum.encode_data(vec![1, 2, 3]).unwrap(), vec![1, 2, 3]);
+        assert_eq!(checksum.decode_data(vec![1, 2, 3]).unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -1,43 +1,332 @@
 //! A middleware for compressing all transmitted data.
 //!
 //! Requires the `middleware-compression` crate feature to be enabled.
+//!
+//! The zstd, LZ4 and Snappy algorithms additionally require the
+//! `middleware-compression-zstd`, `middleware-compression-lz4` and
+//! `middleware-compression-snappy` crate features respectively.
 
-use crate::{wire, Error};
+use crate::{wire, Error, ErrorKind};
 use flate2;
 
 use std::io::prelude::*;
-use std::io::Cursor;
+
+/// The default size below which adaptive compression leaves frames alone.
+pub const DEFAULT_THRESHOLD: usize = 128;
+/// The default limit on the decompressed size of frames.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
 
 /// Defines a compression algorithm.
-#[derive(Copy, Clone, Debug)]
+///
+/// Which algorithms are available depends on the crate features that are
+/// enabled, so matches on this enum need a wildcard arm.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Algorithm
 {
     /// The zlib compression algorithm.
     ///
     /// <https://en.wikipedia.org/wiki/Zlib>
     Zlib,
+    /// Raw deflate data, without a header or checksum.
+    ///
+    /// <https://en.wikipedia.org/wiki/Deflate>
+    Deflate,
+    /// The gzip file format.
+    ///
+    /// <https://en.wikipedia.org/wiki/Gzip>
+    Gzip,
+    /// The Zstandard compression algorithm.
+    ///
+    /// <https://en.wikipedia.org/wiki/Zstd>
+    #[cfg(feature = "middleware-compression-zstd")]
+    Zstd,
+    /// The LZ4 compression algorithm.
+    ///
+    /// <https://en.wikipedia.org/wiki/LZ4_(compression_algorithm)>
+    #[cfg(feature = "middleware-compression-lz4")]
+    Lz4,
+    /// The Snappy compression algorithm.
+    ///
+    /// Snappy has a single compression level, so levels are ignored.
+    ///
+    /// <https://en.wikipedia.org/wiki/Snappy_(compression)>
+    #[cfg(feature = "middleware-compression-snappy")]
+    Snappy,
+}
+
+/// Defines how hard an algorithm should try to compress data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level
+{
+    /// The fastest level of the algorithm.
+    Fastest,
+    /// The level the algorithm recommends by default.
+    Default,
+    /// The level that produces the smallest output.
+    Best,
+    /// An algorithm specific level.
+    ///
+    /// Levels outside of the range the algorithm supports are clamped.
+    /// Deflate based algorithms support levels 0 to 9, zstd supports
+    /// levels 1 to 22, and LZ4 supports levels 1 to 12.
+    Precise(u32),
 }
 
 /// Compression middleware.
+///
+/// Frames that would decompress to more than `DEFAULT_MAX_DECOMPRESSED_SIZE`
+/// bytes are rejected, unless adaptive compression is given another limit.
 #[derive(Clone, Debug)]
 pub enum Compression
 {
     /// No compression or decompression should be applied to the data.
     Disabled,
     /// Compression and decompression should be applied to the data.
+    ///
+    /// Data is compressed at the best level of the algorithm.
     Enabled(Algorithm),
+    /// Compression and decompression should be applied to the data,
+    /// compressing at a specific level.
+    WithLevel(Algorithm, Level),
+    /// Only frames that are worth compressing are compressed.
+    Adaptive(Adaptive),
+}
+
+/// Settings for adaptive compression.
+///
+/// Every frame is prefixed by a varint holding its uncompressed size
+/// and whether it was compressed. Frames smaller than the threshold,
+/// and frames that compression would not shrink, are sent as they are.
+///
+/// The size in the header lets the receiver allocate the right amount
+/// of memory up front, and reject frames that would decompress to more
+/// than `max_decompressed_size` bytes before decompressing them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Adaptive
+{
+    pub algorithm: Algorithm,
+    pub level: Level,
+    /// Frames smaller than this many bytes are never compressed.
+    pub threshold: usize,
+    /// The largest decompressed frame that will be accepted.
+    pub max_decompressed_size: usize,
+}
+
+impl Algorithm
+{
+    /// Compresses some data.
+    pub fn compress(&self, data: &[u8], level: Level) -> Result<Vec<u8>, Error> {
+        match *self {
+            Algorithm::Zlib => {
+                let mut e = flate2::write::ZlibEncoder::new(Vec::new(), level.flate2());
+                e.write_all(data)?;
+                Ok(e.finish()?)
+            },
+            Algorithm::Deflate => {
+                let mut e = flate2::write::DeflateEncoder::new(Vec::new(), level.flate2());
+                e.write_all(data)?;
+                Ok(e.finish()?)
+            },
+            Algorithm::Gzip => {
+                let mut e = flate2::write::GzEncoder::new(Vec::new(), level.flate2());
+                e.write_all(data)?;
+                Ok(e.finish()?)
+            },
+            #[cfg(feature = "middleware-compression-zstd")]
+            Algorithm::Zstd => Ok(zstd::bulk::compress(data, level.zstd())?),
+            #[cfg(feature = "middleware-compression-lz4")]
+            Algorithm::Lz4 => Ok(lz4::block::compress(data, Some(level.lz4()), true)?),
+            #[cfg(feature = "middleware-compression-snappy")]
+            Algorithm::Snappy => Ok(snap::raw::Encoder::new().compress_vec(data).map_err(std::io::Error::from)?),
+        }
+    }
+
+    /// Decompresses some data.
+    ///
+    /// Fails with `ErrorKind::PacketTooLarge` if the data would decompress
+    /// to more than `DEFAULT_MAX_DECOMPRESSED_SIZE` bytes.
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.decompress_with_limit(data, DEFAULT_MAX_DECOMPRESSED_SIZE)
+    }
+
+    /// Decompresses some data, accepting no more than `max_size` bytes
+    /// of output.
+    ///
+    /// Fails with `ErrorKind::PacketTooLarge` if the data would decompress
+    /// to more. Sizes recorded by the compressed data itself are checked
+    /// before any memory is allocated for the output.
+    pub fn decompress_with_limit(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+        let mut decompressed = Vec::new();
+        let limit = max_size as u64 + 1;
+
+        match *self {
+            Algorithm::Zlib => { flate2::read::ZlibDecoder::new(data).take(limit).read_to_end(&mut decompressed)?; },
+            Algorithm::Deflate => { flate2::read::DeflateDecoder::new(data).take(limit).read_to_end(&mut decompressed)?; },
+            Algorithm::Gzip => { flate2::read::GzDecoder::new(data).take(limit).read_to_end(&mut decompressed)?; },
+            #[cfg(feature = "middleware-compression-zstd")]
+            Algorithm::Zstd => { zstd::stream::read::Decoder::new(data)?.take(limit).read_to_end(&mut decompressed)?; },
+            #[cfg(feature = "middleware-compression-lz4")]
+            Algorithm::Lz4 => {
+                use byteorder::ByteOrder;
+
+                if data.len() < 4 {
+                    return Err(ErrorKind::MalformedFrame("frame is missing its size").into());
+                }
+
+                let size = byteorder::LittleEndian::read_u32(data) as usize;
+                if size > max_size {
+                    return Err(ErrorKind::PacketTooLarge(size, max_size).into());
+                }
+                decompressed = lz4::block::decompress(data, None)?;
+            },
+            #[cfg(feature = "middleware-compression-snappy")]
+            Algorithm::Snappy => {
+                let size = snap::raw::decompress_len(data).map_err(std::io::Error::from)?;
+                if size > max_size {
+                    return Err(ErrorKind::PacketTooLarge(size, max_size).into());
+                }
+                decompressed = snap::raw::Decoder::new().decompress_vec(data).map_err(std::io::Error::from)?;
+            },
+        }
+
+        if decompressed.len() > max_size {
+            return Err(ErrorKind::PacketTooLarge(decompressed.len(), max_size).into());
+        }
+        Ok(decompressed)
+    }
+
+    /// Decompresses data that is expected to decompress to a known size.
+    ///
+    /// No more than one byte past the expected size is ever allocated
+    /// or decompressed, so the size can be checked afterwards without
+    /// trusting the compressed data.
+    fn decompress_exact(&self, data: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+        let mut decompressed = Vec::with_capacity(size);
+        let limit = size as u64 + 1;
+
+        match *self {
+            Algorithm::Zlib => { flate2::read::ZlibDecoder::new(data).take(limit).read_to_end(&mut decompressed)?; },
+            Algorithm::Deflate => { flate2::read::DeflateDecoder::new(data).take(limit).read_to_end(&mut decompressed)?; },
+            Algorithm::Gzip => { flate2::read::GzDecoder::new(data).take(limit).read_to_end(&mut decompressed)?; },
+            #[cfg(feature = "middleware-compression-zstd")]
+            Algorithm::Zstd => { zstd::stream::read::Decoder::new(data)?.take(limit).read_to_end(&mut decompressed)?; },
+            // LZ4 and Snappy record the decompressed size themselves.
+            #[cfg(feature = "middleware-compression-lz4")]
+            Algorithm::Lz4 => {
+                use byteorder::ByteOrder;
+
+                if data.len() < 4 || byteorder::LittleEndian::read_u32(data) as usize != size {
+                    return Err(ErrorKind::MalformedFrame("frame size does not match its header").into());
+                }
+                decompressed = lz4::block::decompress(data, None)?;
+            },
+            #[cfg(feature = "middleware-compression-snappy")]
+            Algorithm::Snappy => {
+                if snap::raw::decompress_len(data).map_err(std::io::Error::from)? != size {
+                    return Err(ErrorKind::MalformedFrame("frame size does not match its header").into());
+                }
+                decompressed = snap::raw::Decoder::new().decompress_vec(data).map_err(std::io::Error::from)?;
+            },
+        }
+
+        Ok(decompressed)
+    }
+}
+
+impl Adaptive
+{
+    /// Creates adaptive compression settings with the default threshold
+    /// and decompressed size limit.
+    pub fn new(algorithm: Algorithm, level: Level) -> Self {
+        Adaptive {
+            algorithm,
+            level,
+            threshold: DEFAULT_THRESHOLD,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let size = data.len();
+        let compressed = if size >= self.threshold {
+            Some(self.algorithm.compress(&data, self.level)?).filter(|compressed| compressed.len() < size)
+        } else {
+            None
+        };
+
+        let mut frame = Vec::with_capacity(MAX_VARINT_SIZE + compressed.as_ref().map_or(size, Vec::len));
+        write_varint(&mut frame, ((size as u64) << 1) | compressed.is_some() as u64);
+        frame.extend_from_slice(compressed.as_ref().unwrap_or(&data));
+
+        Ok(frame)
+    }
+
+    fn decompress(&self, mut frame: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let (header, header_size) = read_varint(&frame)?;
+        let is_compressed = header & 1 == 1;
+        let size = header >> 1;
+
+        if size > self.max_decompressed_size as u64 {
+            return Err(ErrorKind::PacketTooLarge(size as usize, self.max_decompressed_size).into());
+        }
+        let size = size as usize;
+
+        let data = if is_compressed {
+            self.algorithm.decompress_exact(&frame[header_size..], size)?
+        } else {
+            frame.drain(..header_size);
+            frame
+        };
+
+        if data.len() != size {
+            return Err(ErrorKind::MalformedFrame("frame size does not match its header").into());
+        }
+        Ok(data)
+    }
+}
+
+impl Level
+{
+    pub(crate) fn flate2(&self) -> flate2::Compression {
+        match *self {
+            Level::Fastest => flate2::Compression::fast(),
+            Level::Default => flate2::Compression::default(),
+            Level::Best => flate2::Compression::best(),
+            Level::Precise(level) => flate2::Compression::new(level.min(9)),
+        }
+    }
+
+    #[cfg(feature = "middleware-compression-zstd")]
+    pub(crate) fn zstd(&self) -> i32 {
+        match *self {
+            Level::Fastest => 1,
+            Level::Default => zstd::DEFAULT_COMPRESSION_LEVEL,
+            Level::Best => 19,
+            Level::Precise(level) => level.clamp(1, 22) as i32,
+        }
+    }
+
+    #[cfg(feature = "middleware-compression-lz4")]
+    fn lz4(&self) -> lz4::block::CompressionMode {
+        use lz4::block::CompressionMode;
+
+        match *self {
+            Level::Fastest => CompressionMode::FAST(1),
+            Level::Default => CompressionMode::DEFAULT,
+            Level::Best => CompressionMode::HIGHCOMPRESSION(12),
+            Level::Precise(level) => CompressionMode::HIGHCOMPRESSION(level.clamp(1, 12) as i32),
+        }
+    }
 }
 
 impl wire::Middleware for Compression
 {
     fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
         match *self {
-            Compression::Enabled(algorithm) => match algorithm {
-                Algorithm::Zlib => {
-                    let e = flate2::write::ZlibEncoder::new(data, flate2::Compression::best());
-                    Ok(e.finish()?)
-                },
-            },
+            Compression::Enabled(algorithm) => algorithm.compress(&data, Level::Best),
+            Compression::WithLevel(algorithm, level) => algorithm.compress(&data, level),
+            Compression::Adaptive(ref adaptive) => adaptive.compress(data),
             Compression::Disabled => Ok(data),
         }
     }
@@ -45,15 +334,217 @@ impl wire::Middleware for Compression
     /// Un-processes some data.
     fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
         match *self {
-            Compression::Enabled(algorithm) => match algorithm {
-                Algorithm::Zlib => {
-                    let d = flate2::read::ZlibDecoder::new(Cursor::new(data));
-                    let bytes: Result<Vec<u8>, _> = d.bytes().collect();
-                    Ok(bytes?)
-                },
-            },
+            Compression::Enabled(algorithm) |
+                Compression::WithLevel(algorithm, _) => algorithm.decompress(&data),
+            Compression::Adaptive(ref adaptive) => adaptive.decompress(data),
             Compression::Disabled => Ok(data),
         }
     }
+
+    fn is_noop(&self) -> bool {
+        match *self {
+            Compression::Disabled => true,
+            Compression::Enabled(..) |
+                Compression::WithLevel(..) |
+                Compression::Adaptive(..) => false,
+        }
+    }
+}
+
+/// The largest number of bytes a `u64` varint takes up.
+const MAX_VARINT_SIZE: usize = 10;
+
+/// Writes an unsigned LEB128 varint.
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// Reads an unsigned LEB128 varint, returning it and its size in bytes.
+fn read_varint(buffer: &[u8]) -> Result<(u64, usize), Error> {
+    let mut value = 0u64;
+
+    for (index, &byte) in buffer.iter().enumerate().take(MAX_VARINT_SIZE) {
+        value |= ((byte & 0x7f) as u64) << (7 * index);
+
+        if byte & 0x80 == 0 {
+            return Ok((value, index + 1));
+        }
+    }
+
+    Err(ErrorKind::MalformedFrame("frame has an invalid compression header").into())
 }
 
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use crate::wire::Middleware;
+
+    fn algorithms() -> Vec<Algorithm> {
+        vec![
+            Algorithm::Zlib,
+            Algorithm::Deflate,
+            Algorithm::Gzip,
+            #[cfg(feature = "middleware-compression-zstd")] Algorithm::Zstd,
+            #[cfg(feature = "middleware-compression-lz4")] Algorithm::Lz4,
+            #[cfg(feature = "middleware-compression-snappy")] Algorithm::Snappy,
+        ]
+    }
+
+    fn sample() -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog. ".iter().cycle().take(4000).cloned().collect()
+    }
+
+    #[test]
+    fn data_is_compressed_and_decompressed() {
+        for algorithm in algorithms() {
+            for &level in &[Level::Fastest, Level::Default, Level::Best, Level::Precise(3), Level::Precise(100)] {
+                let mut compression = Compression::WithLevel(algorithm, level);
+
+                let compressed = compression.encode_data(sample()).unwrap();
+                assert!(compressed.len() < sample().len() / 4, "{:?} at {:?} did not compress", algorithm, level);
+                assert_eq!(compression.decode_data(compressed).unwrap(), sample());
+            }
+        }
+    }
+
+    #[test]
+    fn enabled_compression_uses_the_best_level() {
+        for algorithm in algorithms() {
+            let compressed = Compression::Enabled(algorithm).encode_data(sample()).unwrap();
+
+            assert_eq!(compressed, algorithm.compress(&sample(), Level::Best).unwrap());
+        }
+    }
+
+    #[test]
+    fn corrupted_data_is_rejected() {
+        for algorithm in algorithms() {
+            let mut compression = Compression::Enabled(algorithm);
+
+            assert!(compression.decode_data(vec![0xff; 64]).is_err(), "{:?} accepted garbage", algorithm);
+        }
+    }
+
+    #[test]
+    fn disabled_compression_leaves_data_alone() {
+        let mut compression = Compression::Disabled;
+
+        assert!(compression.is_noop());
+        assert_eq!(compression.encode_data(sample()).unwrap(), sample());
+        assert_eq!(compression.decode_data(sample()).unwrap(), sample());
+    }
+
+    #[test]
+    fn adaptive_compression_skips_small_frames() {
+        for algorithm in algorithms() {
+            let mut compression = Compression::Adaptive(Adaptive::new(algorithm, Level::Default));
+
+            let frame = compression.encode_data(vec![7; 6]).unwrap();
+            assert_eq!(frame, vec![6 << 1, 7, 7, 7, 7, 7, 7]);
+            assert_eq!(compression.decode_data(frame).unwrap(), vec![7; 6]);
+        }
+    }
+
+    #[test]
+    fn adaptive_compression_compresses_large_frames() {
+        for algorithm in algorithms() {
+            let mut compression = Compression::Adaptive(Adaptive::new(algorithm, Level::Default));
+
+            let frame = compression.encode_data(sample()).unwrap();
+            assert_eq!(read_varint(&frame).unwrap(), (((sample().len() as u64) << 1) | 1, 2));
+            assert!(frame.len() < sample().len() / 4);
+            assert_eq!(compression.decode_data(frame).unwrap(), sample());
+        }
+    }
+
+    #[test]
+    fn adaptive_compression_skips_incompressible_frames() {
+        // Pseudo-random bytes from an xorshift generator.
+        let data: Vec<u8> = (0..1000).scan(0x2545_f491_u32, |state, _| {
+            *state ^= *state << 13;
+            *state ^= *state >> 17;
+            *state ^= *state << 5;
+            Some(*state as u8)
+        }).collect();
+        let mut compression = Compression::Adaptive(Adaptive { threshold: 0, ..Adaptive::new(Algorithm::Zlib, Level::Best) });
+
+        let frame = compression.encode_data(data.clone()).unwrap();
+        assert_eq!(frame.len(), data.len() + 2);
+        assert_eq!(frame[0] & 1, 0);
+        assert_eq!(compression.decode_data(frame).unwrap(), data);
+    }
+
+    #[test]
+    fn decompression_bombs_are_rejected() {
+        for algorithm in algorithms() {
+            let mut sender = Compression::Adaptive(Adaptive::new(algorithm, Level::Default));
+            let mut receiver = Compression::Adaptive(Adaptive { max_decompressed_size: 1000, ..Adaptive::new(algorithm, Level::Default) });
+
+            match receiver.decode_data(sender.encode_data(sample()).unwrap()) {
+                Err(Error(ErrorKind::PacketTooLarge(4000, 1000), _)) => (),
+                result => panic!("{:?} accepted a frame over the limit: {:?}", algorithm, result.map(|data| data.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn decompressed_size_is_limited() {
+        for algorithm in algorithms() {
+            let compressed = algorithm.compress(&sample(), Level::Default).unwrap();
+
+            assert_eq!(algorithm.decompress_with_limit(&compressed, 4000).unwrap(), sample());
+            match algorithm.decompress_with_limit(&compressed, 3999) {
+                Err(Error(ErrorKind::PacketTooLarge(_, 3999), _)) => (),
+                result => panic!("{:?} accepted data over the limit: {:?}", algorithm, result.map(|data| data.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn recorded_sizes_are_checked_before_decompressing() {
+        #[cfg(feature = "middleware-compression-lz4")]
+        match Compression::Enabled(Algorithm::Lz4).decode_data(vec![0xff, 0xff, 0xff, 0x7f]) {
+            Err(Error(ErrorKind::PacketTooLarge(0x7fff_ffff, DEFAULT_MAX_DECOMPRESSED_SIZE), _)) => (),
+            result => panic!("LZ4 trusted its size prefix: {:?}", result.map(|data| data.len())),
+        }
+
+        // A varint of 2^31 - 1.
+        #[cfg(feature = "middleware-compression-snappy")]
+        match Compression::Enabled(Algorithm::Snappy).decode_data(vec![0xff, 0xff, 0xff, 0xff, 0x07]) {
+            Err(Error(ErrorKind::PacketTooLarge(0x7fff_ffff, DEFAULT_MAX_DECOMPRESSED_SIZE), _)) => (),
+            result => panic!("Snappy trusted its size prefix: {:?}", result.map(|data| data.len())),
+        }
+    }
+
+    #[test]
+    fn frames_that_do_not_match_their_header_are_rejected() {
+        for algorithm in algorithms() {
+            let mut compression = Compression::Adaptive(Adaptive::new(algorithm, Level::Default));
+
+            // Claims to decompress to 100 bytes.
+            let mut frame = vec![];
+            write_varint(&mut frame, (100 << 1) | 1);
+            frame.extend(algorithm.compress(&sample(), Level::Default).unwrap());
+
+            assert!(compression.decode_data(frame).is_err(), "{:?} accepted a lying header", algorithm);
+            assert!(compression.decode_data(vec![10 << 1, 1, 2, 3]).is_err());
+            assert!(compression.decode_data(vec![0xff; 3]).is_err());
+        }
+    }
+
+    #[test]
+    fn varints_are_read_back() {
+        for &value in &[0, 1, 127, 128, 300, 1 << 40, u64::MAX] {
+            let mut buffer = vec![];
+            write_varint(&mut buffer, value);
+
+            assert!(buffer.len() <= MAX_VARINT_SIZE);
+            assert_eq!(read_varint(&buffer).unwrap(), (value, buffer.len()));
+        }
+    }
+}
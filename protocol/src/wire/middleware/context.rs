@@ -0,0 +1,139 @@
+//! Information about the packet that middleware is processing.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Which way a frame is passing through the middleware.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction
+{
+    /// The frame is being encoded before it is sent.
+    Outgoing,
+    /// The frame is being decoded after it was received.
+    Incoming,
+}
+
+/// Per-packet information passed to middleware along with a frame.
+///
+/// Middleware can use this to treat packets differently, for example
+/// to leave handshake packets unencrypted or to only compress certain
+/// message types.
+#[derive(Debug)]
+pub struct Context
+{
+    /// Whether the frame is being sent or received.
+    pub direction: Direction,
+    /// The number of frames that were sent or received before this one,
+    /// in the same direction.
+    pub sequence: u64,
+    /// The `Parcel::TYPE_NAME` of the packet.
+    ///
+    /// This is only known when sending, as received frames have not
+    /// been parsed yet.
+    pub type_name: Option<&'static str>,
+    /// Values attached by the application or by earlier middleware.
+    pub metadata: Metadata,
+}
+
+/// A set of values keyed by their type.
+///
+/// Applications should wrap their values in their own types so that
+/// they do not clash with values attached by other code.
+#[derive(Default)]
+pub struct Metadata
+{
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Context
+{
+    /// Creates a context for an outgoing frame.
+    pub fn outgoing(sequence: u64,
+                    type_name: &'static str,
+                    metadata: Metadata) -> Self {
+        Context { direction: Direction::Outgoing, sequence, type_name: Some(type_name), metadata }
+    }
+
+    /// Creates a context for an incoming frame.
+    pub fn incoming(sequence: u64) -> Self {
+        Context { direction: Direction::Incoming, sequence, type_name: None, metadata: Metadata::new() }
+    }
+}
+
+impl Metadata
+{
+    /// Creates an empty set of metadata.
+    pub fn new() -> Self {
+        Metadata::default()
+    }
+
+    /// Attaches a value, returning the previous value of the same type.
+    pub fn insert<T>(&mut self, value: T) -> Option<T>
+        where T: Any + Send + Sync {
+        self.values.insert(TypeId::of::<T>(), Box::new(value))
+                   .map(|previous| *previous.downcast().expect("metadata is keyed by type"))
+    }
+
+    /// Gets the value of a type.
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    /// Gets the value of a type mutably.
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.values.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    /// Removes the value of a type.
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.values.remove(&TypeId::of::<T>())
+                   .map(|value| *value.downcast().expect("metadata is keyed by type"))
+    }
+
+    /// Checks if there is a value of a type.
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Gets the number of values.
+    pub fn len(&self) -> usize { self.values.len() }
+
+    /// Checks if there are no values.
+    pub fn is_empty(&self) -> bool { self.values.is_empty() }
+}
+
+impl fmt::Debug for Metadata
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // The values are not necessarily `Debug`.
+        fmt.debug_struct("Metadata")
+           .field("len", &self.len())
+           .finish()
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Priority(u8);
+
+    #[test]
+    fn values_are_keyed_by_type() {
+        let mut metadata = Metadata::new();
+        assert_eq!(metadata.insert(Priority(1)), None);
+        assert_eq!(metadata.insert("peer"), None);
+        assert_eq!(metadata.insert(Priority(2)), Some(Priority(1)));
+
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata.get::<&str>(), Some(&"peer"));
+
+        metadata.get_mut::<Priority>().unwrap().0 += 1;
+        assert_eq!(metadata.remove::<Priority>(), Some(Priority(3)));
+        assert!(!metadata.contains::<Priority>());
+        assert_eq!(metadata.get::<u32>(), None);
+    }
+}
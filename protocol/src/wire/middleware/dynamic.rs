@@ -0,0 +1,296 @@
+//! A middleware pipeline that can be changed at runtime.
+
+use crate::{wire::middleware::{Context, Frame, Middleware, Pipeline}, Error};
+
+/// A middleware pipeline whose stages can be changed at runtime.
+///
+/// Stages are named so that they can be found again later, for
+/// example to enable encryption once a client has logged in. Data is
+/// encoded by the stages in order, and decoded by them in reverse.
+///
+/// Unlike pipelines defined with `define_middleware_pipeline!`, dynamic
+/// pipelines cannot be cloned. A datagram endpoint can still use them by
+/// building one for every peer in the closure it is created with.
+///
+/// # Example
+///
+/// ```
+/// use protocol::wire::middleware::{rotate_bytes::RotateBytes, DynamicPipeline, Pipeline};
+///
+/// let mut pipeline = DynamicPipeline::new();
+/// pipeline.push("rotate", RotateBytes::ROT13);
+///
+/// assert_eq!(pipeline.encode_data(vec![1, 2, 3]).unwrap(), vec![14, 15, 16]);
+///
+/// pipeline.remove("rotate");
+/// assert_eq!(pipeline.encode_data(vec![1, 2, 3]).unwrap(), vec![1, 2, 3]);
+/// ```
+#[derive(Debug, Default)]
+pub struct DynamicPipeline
+{
+    stages: Vec<Stage>,
+}
+
+#[derive(Debug)]
+struct Stage
+{
+    name: String,
+    middleware: Box<dyn Middleware + Send>,
+}
+
+impl DynamicPipeline
+{
+    /// Creates a new pipeline without any stages.
+    pub fn new() -> Self {
+        DynamicPipeline::default()
+    }
+
+    /// Adds a stage after all existing stages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is already a stage with the same name.
+    pub fn push<S, M>(&mut self, name: S, middleware: M)
+        where S: Into<String>, M: Middleware + Send + 'static {
+        let index = self.stages.len();
+        self.insert(index, name, middleware)
+    }
+
+    /// Adds a stage at a position in the pipeline.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`, or if there is already a stage with the
+    /// same name.
+    pub fn insert<S, M>(&mut self, index: usize, name: S, middleware: M)
+        where S: Into<String>, M: Middleware + Send + 'static {
+        let name = name.into();
+        assert!(!self.contains(&name), "pipeline already has a stage named '{}'", name);
+
+        self.stages.insert(index, Stage { name, middleware: Box::new(middleware) });
+    }
+
+    /// Adds a stage directly before another one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no stage named `before`, or if there is
+    /// already a stage with the same name.
+    pub fn insert_before<S, M>(&mut self, before: &str, name: S, middleware: M)
+        where S: Into<String>, M: Middleware + Send + 'static {
+        let index = self.expect_position(before);
+        self.insert(index, name, middleware)
+    }
+
+    /// Adds a stage directly after another one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no stage named `after`, or if there is
+    /// already a stage with the same name.
+    pub fn insert_after<S, M>(&mut self, after: &str, name: S, middleware: M)
+        where S: Into<String>, M: Middleware + Send + 'static {
+        let index = self.expect_position(after) + 1;
+        self.insert(index, name, middleware)
+    }
+
+    /// Removes a stage, returning its middleware.
+    pub fn remove(&mut self, name: &str) -> Option<Box<dyn Middleware + Send>> {
+        self.position(name).map(|index| self.stages.remove(index).middleware)
+    }
+
+    /// Replaces the middleware of a stage, keeping its position.
+    ///
+    /// Returns the old middleware, or `None` if there is no such stage
+    /// in which case the pipeline is left unchanged.
+    pub fn replace<M>(&mut self, name: &str, middleware: M) -> Option<Box<dyn Middleware + Send>>
+        where M: Middleware + Send + 'static {
+        let stage = self.stages.iter_mut().find(|stage| stage.name == name)?;
+        Some(std::mem::replace(&mut stage.middleware, Box::new(middleware)))
+    }
+
+    /// Gets the middleware of a stage.
+    pub fn get(&self, name: &str) -> Option<&(dyn Middleware + Send)> {
+        self.stages.iter().find(|stage| stage.name == name).map(|stage| &*stage.middleware)
+    }
+
+    /// Gets the middleware of a stage mutably.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut (dyn Middleware + Send)> {
+        match self.stages.iter_mut().find(|stage| stage.name == name) {
+            Some(stage) => Some(&mut *stage.middleware),
+            None => None,
+        }
+    }
+
+    /// Checks if the pipeline has a stage.
+    pub fn contains(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    /// Gets the names of all stages, in encoding order.
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.stages.iter().map(|stage| &stage.name[..])
+    }
+
+    /// Gets the number of stages.
+    pub fn len(&self) -> usize { self.stages.len() }
+
+    /// Checks if the pipeline has no stages.
+    pub fn is_empty(&self) -> bool { self.stages.is_empty() }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|stage| stage.name == name)
+    }
+
+    fn expect_position(&self, name: &str) -> usize {
+        self.position(name).unwrap_or_else(|| panic!("pipeline has no stage named '{}'", name))
+    }
+}
+
+impl Pipeline for DynamicPipeline
+{
+    fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.encode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.decode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn encode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        for stage in self.stages.iter_mut() {
+            stage.middleware.encode_frame(frame)?;
+        }
+
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        for stage in self.stages.iter_mut().rev() {
+            stage.middleware.decode_frame(frame)?;
+        }
+
+        Ok(())
+    }
+
+    fn encode_with_context(&mut self,
+                           frame: &mut Frame,
+                           context: &mut Context) -> Result<(), Error> {
+        for stage in self.stages.iter_mut() {
+            stage.middleware.encode_with_context(frame, context)?;
+        }
+
+        Ok(())
+    }
+
+    fn decode_with_context(&mut self,
+                           frame: &mut Frame,
+                           context: &mut Context) -> Result<(), Error> {
+        for stage in self.stages.iter_mut().rev() {
+            stage.middleware.decode_with_context(frame, context)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_noop(&self) -> bool {
+        self.stages.iter().all(|stage| stage.middleware.is_noop())
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::DynamicPipeline;
+    use crate::wire::middleware::{rotate_bytes::RotateBytes, Middleware, Pipeline};
+    use crate::Error;
+
+    /// Appends a marker byte, and checks that it is the last byte when
+    /// decoding.
+    #[derive(Debug)]
+    struct Marker(u8);
+
+    impl Middleware for Marker
+    {
+        fn encode_data(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+            data.push(self.0);
+            Ok(data)
+        }
+
+        fn decode_data(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+            assert_eq!(data.pop(), Some(self.0), "stages were decoded out of order");
+            Ok(data)
+        }
+    }
+
+    #[test]
+    fn stages_are_decoded_in_reverse() {
+        let mut pipeline = DynamicPipeline::new();
+        pipeline.push("first", Marker(1));
+        pipeline.push("second", Marker(2));
+        pipeline.push("rotate", RotateBytes { amount: 10 });
+
+        let encoded = pipeline.encode_data(vec![0]).unwrap();
+        assert_eq!(encoded, vec![10, 11, 12]);
+        assert_eq!(pipeline.decode_data(encoded).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn stages_can_be_inserted_relative_to_each_other() {
+        let mut pipeline = DynamicPipeline::new();
+        pipeline.push("b", Marker(2));
+        pipeline.insert_before("b", "a", Marker(1));
+        pipeline.insert_after("b", "d", Marker(4));
+        pipeline.insert_after("b", "c", Marker(3));
+        pipeline.insert(0, "start", Marker(0));
+
+        assert_eq!(pipeline.names().collect::<Vec<_>>(), vec!["start", "a", "b", "c", "d"]);
+        assert_eq!(pipeline.encode_data(vec![]).unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(pipeline.decode_data(vec![0, 1, 2, 3, 4]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn stages_can_be_removed_and_replaced() {
+        let mut pipeline = DynamicPipeline::new();
+        pipeline.push("marker", Marker(1));
+        pipeline.push("rotate", RotateBytes { amount: 0 });
+        assert!(!pipeline.is_noop());
+
+        assert!(pipeline.remove("marker").is_some());
+        assert!(pipeline.remove("marker").is_none());
+        assert!(pipeline.is_noop());
+
+        assert!(pipeline.replace("rotate", RotateBytes::ROT13).unwrap().is_noop());
+        assert!(pipeline.replace("missing", RotateBytes::ROT13).is_none());
+        assert_eq!(pipeline.len(), 1);
+        assert!(!pipeline.get("rotate").unwrap().is_noop());
+        assert_eq!(pipeline.encode_data(vec![0]).unwrap(), vec![13]);
+    }
+
+    #[test]
+    fn stages_can_be_enabled_between_packets() {
+        let (mut sender, mut receiver) = (DynamicPipeline::new(), DynamicPipeline::new());
+
+        let plain = sender.encode_data(vec![1]).unwrap();
+        assert_eq!(receiver.decode_data(plain).unwrap(), vec![1]);
+
+        sender.push("rotate", RotateBytes::ROT13);
+        receiver.push("rotate", RotateBytes::ROT13);
+
+        let rotated = sender.encode_data(vec![1]).unwrap();
+        assert_eq!(rotated, vec![14]);
+        assert_eq!(receiver.decode_data(rotated).unwrap(), vec![1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "already has a stage named 'a'")]
+    fn stage_names_are_unique() {
+        let mut pipeline = DynamicPipeline::new();
+        pipeline.push("a", Marker(1));
+        pipeline.push("a", Marker(2));
+    }
+}
@@ -0,0 +1,453 @@
+//! A middleware for authenticated encryption of all transmitted data.
+//!
+//! Requires the `middleware-encryption` crate feature to be enabled.
+//!
+//! Every frame is prefixed by a header holding the key epoch and the
+//! sender's frame counter. The nonce is built from the sender's side,
+//! the epoch and the counter, so both ends can share one key without
+//! ever reusing a nonce. The header is authenticated along with the
+//! data, and frames that fail authentication or have already been
+//! received are rejected.
+//!
+//! Keys are rotated by calling `Encryption::rotate_key` with the same
+//! new key on both ends, typically right after sending or receiving a
+//! packet that announces the new key. The previous key is kept so that
+//! frames which were already in flight can still be decrypted.
+
+use crate::{wire, wire::middleware::{replay::ReplayWindow, Frame}, Error, ErrorKind};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
+
+/// The size of a key in bytes.
+pub const KEY_SIZE: usize = 32;
+/// The size of the header prepended to each frame.
+pub const HEADER_SIZE: usize = 10;
+/// The size of the authentication tag appended to each frame.
+pub const TAG_SIZE: usize = 16;
+
+/// Defines an authenticated encryption algorithm.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm
+{
+    /// ChaCha20 with a Poly1305 authenticator.
+    ///
+    /// <https://tools.ietf.org/html/rfc8439>
+    ChaCha20Poly1305,
+    /// AES with a 256-bit key in Galois/Counter Mode.
+    ///
+    /// <https://en.wikipedia.org/wiki/Galois/Counter_Mode>
+    Aes256Gcm,
+}
+
+/// Which end of a connection the middleware is on.
+///
+/// The two ends of a connection must be on different sides.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Side
+{
+    Initiator,
+    Responder,
+}
+
+/// Authenticated encryption middleware.
+///
+/// Cloning the middleware copies its key and frame counters, so a clone
+/// and the original would encrypt with the same nonces. Only one of them
+/// may ever send. `Clone` exists so that the middleware can be used with
+/// `define_middleware_pipeline!`. Datagram endpoints create middleware for
+/// every peer with a closure, which should give each peer its own key.
+#[derive(Clone, Debug)]
+pub struct Encryption
+{
+    side: Side,
+    current: Epoch,
+    previous: Option<Epoch>,
+    send_counter: u64,
+}
+
+/// The state kept for a single key.
+#[derive(Clone, Debug)]
+struct Epoch
+{
+    number: u16,
+    cipher: Cipher,
+    replay_window: ReplayWindow,
+}
+
+#[derive(Clone)]
+enum Cipher
+{
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+impl Encryption
+{
+    /// Creates a new encryption middleware.
+    pub fn new(algorithm: Algorithm,
+               key: &[u8; KEY_SIZE],
+               side: Side) -> Self {
+        Encryption {
+            side,
+            current: Epoch::new(0, Cipher::new(algorithm, key)),
+            previous: None,
+            send_counter: 0,
+        }
+    }
+
+    /// Gets the algorithm currently in use.
+    pub fn algorithm(&self) -> Algorithm {
+        self.current.cipher.algorithm()
+    }
+
+    /// Gets the current key epoch.
+    ///
+    /// Starts at zero and is incremented every time the key is rotated.
+    pub fn epoch(&self) -> u16 {
+        self.current.number
+    }
+
+    /// Switches to a new key, starting from the next packet.
+    ///
+    /// Frames encrypted with the key that is being replaced can still
+    /// be decrypted until the key is rotated again.
+    pub fn rotate_key(&mut self, key: &[u8; KEY_SIZE]) {
+        self.rotate_key_with(self.algorithm(), key)
+    }
+
+    /// Switches to a new key and algorithm, starting from the next packet.
+    pub fn rotate_key_with(&mut self,
+                           algorithm: Algorithm,
+                           key: &[u8; KEY_SIZE]) {
+        let next = Epoch::new(self.current.number.wrapping_add(1), Cipher::new(algorithm, key));
+
+        self.previous = Some(std::mem::replace(&mut self.current, next));
+        self.send_counter = 0;
+    }
+
+    fn epoch_mut(&mut self, number: u16) -> Option<&mut Epoch> {
+        if self.current.number == number {
+            Some(&mut self.current)
+        } else {
+            self.previous.as_mut().filter(|previous| previous.number == number)
+        }
+    }
+}
+
+impl wire::Middleware for Encryption
+{
+    fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.encode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.decode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn encode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let header = header(self.current.number, counter);
+        let nonce = nonce(self.side, self.current.number, counter);
+        let tag = self.current.cipher.encrypt(&nonce, &header, frame)?;
+
+        frame.prepend(&header);
+        frame.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        if frame.len() < HEADER_SIZE + TAG_SIZE {
+            return Err(ErrorKind::DecryptionFailed.into());
+        }
+
+        let epoch_number = BigEndian::read_u16(&frame[0..2]);
+        let counter = BigEndian::read_u64(&frame[2..HEADER_SIZE]);
+        let nonce = nonce(self.side.peer(), epoch_number, counter);
+
+        let epoch = self.epoch_mut(epoch_number).ok_or(ErrorKind::DecryptionFailed)?;
+
+        if !epoch.replay_window.check(counter) {
+            return Err(ErrorKind::ReplayedFrame(counter).into());
+        }
+
+        let tag_start = frame.len() - TAG_SIZE;
+        let (header, rest) = frame.split_at_mut(HEADER_SIZE);
+        let (body, tag) = rest.split_at_mut(tag_start - HEADER_SIZE);
+        epoch.cipher.decrypt(&nonce, header, body, tag)?;
+        epoch.replay_window.accept(counter);
+
+        frame.truncate(tag_start);
+        frame.advance(HEADER_SIZE);
+        Ok(())
+    }
+}
+
+impl Side
+{
+    fn peer(self) -> Side {
+        match self {
+            Side::Initiator => Side::Responder,
+            Side::Responder => Side::Initiator,
+        }
+    }
+}
+
+impl Epoch
+{
+    fn new(number: u16, cipher: Cipher) -> Self {
+        Epoch { number, cipher, replay_window: ReplayWindow::new() }
+    }
+}
+
+impl Cipher
+{
+    fn new(algorithm: Algorithm, key: &[u8; KEY_SIZE]) -> Self {
+        match algorithm {
+            Algorithm::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new(key.into()))),
+            Algorithm::Aes256Gcm => Cipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+        }
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        match *self {
+            Cipher::ChaCha20Poly1305(..) => Algorithm::ChaCha20Poly1305,
+            Cipher::Aes256Gcm(..) => Algorithm::Aes256Gcm,
+        }
+    }
+
+    /// Encrypts data in place, returning the authentication tag.
+    fn encrypt(&self,
+               nonce: &[u8; 12],
+               associated_data: &[u8],
+               data: &mut [u8]) -> Result<[u8; TAG_SIZE], Error> {
+        let tag = match *self {
+            Cipher::ChaCha20Poly1305(ref cipher) => cipher.encrypt_in_place_detached(nonce.into(), associated_data, data),
+            Cipher::Aes256Gcm(ref cipher) => cipher.encrypt_in_place_detached(nonce.into(), associated_data, data),
+        };
+
+        // Encryption only fails if the data is longer than the
+        // algorithm allows, which is about 2^36 bytes for AES-GCM.
+        let tag = tag.map_err(|_| ErrorKind::PacketTooLarge(data.len(), (1u64 << 36) as usize))?;
+        Ok(tag.into())
+    }
+
+    /// Decrypts data in place, verifying the authentication tag.
+    fn decrypt(&self,
+               nonce: &[u8; 12],
+               associated_data: &[u8],
+               data: &mut [u8],
+               tag: &[u8]) -> Result<(), Error> {
+        let result = match *self {
+            Cipher::ChaCha20Poly1305(ref cipher) => cipher.decrypt_in_place_detached(nonce.into(), associated_data, data, tag.into()),
+            Cipher::Aes256Gcm(ref cipher) => cipher.decrypt_in_place_detached(nonce.into(), associated_data, data, tag.into()),
+        };
+
+        result.map_err(|_| ErrorKind::DecryptionFailed.into())
+    }
+}
+
+impl fmt::Debug for Cipher
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // Never print key material.
+        write!(fmt, "{:?}", self.algorithm())
+    }
+}
+
+fn header(epoch: u16, counter: u64) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    BigEndian::write_u16(&mut header[0..2], epoch);
+    BigEndian::write_u64(&mut header[2..], counter);
+    header
+}
+
+/// Builds the nonce for a frame.
+///
+/// The sender's side is included so that the two directions of a
+/// connection never use the same nonce.
+fn nonce(sender: Side, epoch: u16, counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[0] = match sender { Side::Initiator => 0, Side::Responder => 1 };
+    BigEndian::write_u16(&mut nonce[2..4], epoch);
+    BigEndian::write_u64(&mut nonce[4..], counter);
+    nonce
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use crate::wire::middleware::{rotate_bytes::RotateBytes, Pipeline};
+    use crate::wire::Middleware;
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+    const OTHER_KEY: [u8; KEY_SIZE] = [9; KEY_SIZE];
+
+    fn pair(algorithm: Algorithm) -> (Encryption, Encryption) {
+        (Encryption::new(algorithm, &KEY, Side::Initiator),
+         Encryption::new(algorithm, &KEY, Side::Responder))
+    }
+
+    #[test]
+    fn data_is_encrypted_and_decrypted() {
+        for &algorithm in &[Algorithm::ChaCha20Poly1305, Algorithm::Aes256Gcm] {
+            let (mut initiator, mut responder) = pair(algorithm);
+
+            let frame = initiator.encode_data(b"attack at dawn".to_vec()).unwrap();
+            assert_eq!(frame.len(), HEADER_SIZE + 14 + TAG_SIZE);
+            assert!(!frame.windows(6).any(|w| w == b"attack"));
+            assert_eq!(responder.decode_data(frame).unwrap(), b"attack at dawn");
+
+            let reply = responder.encode_data(b"ok".to_vec()).unwrap();
+            assert_eq!(initiator.decode_data(reply).unwrap(), b"ok");
+        }
+    }
+
+    #[test]
+    fn nonces_are_not_reused() {
+        let (mut initiator, mut responder) = pair(Algorithm::ChaCha20Poly1305);
+
+        let first = initiator.encode_data(vec![1, 2, 3]).unwrap();
+        let second = initiator.encode_data(vec![1, 2, 3]).unwrap();
+        let reply = responder.encode_data(vec![1, 2, 3]).unwrap();
+
+        assert_ne!(first, second);
+        // Both sides start at counter zero, but their ciphertexts differ.
+        assert_eq!(first[..HEADER_SIZE], reply[..HEADER_SIZE]);
+        assert_ne!(first[HEADER_SIZE..], reply[HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn tampered_frames_are_rejected() {
+        let (mut initiator, mut responder) = pair(Algorithm::Aes256Gcm);
+
+        for index in &[0, 5, HEADER_SIZE, HEADER_SIZE + 3 + TAG_SIZE - 1] {
+            let mut frame = initiator.encode_data(vec![1, 2, 3]).unwrap();
+            frame[*index] ^= 0x40;
+
+            match responder.decode_data(frame) {
+                Err(Error(ErrorKind::DecryptionFailed, _)) |
+                    Err(Error(ErrorKind::ReplayedFrame(..), _)) => (),
+                result => panic!("tampered frame was accepted: {:?}", result),
+            }
+        }
+
+        assert!(responder.decode_data(vec![0; HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn frames_from_the_same_side_are_rejected() {
+        let (mut initiator, _) = pair(Algorithm::ChaCha20Poly1305);
+        let mut other_initiator = initiator.clone();
+
+        let frame = initiator.encode_data(vec![1, 2, 3]).unwrap();
+        assert!(other_initiator.decode_data(frame).is_err());
+    }
+
+    #[test]
+    fn replayed_frames_are_rejected() {
+        let (mut initiator, mut responder) = pair(Algorithm::ChaCha20Poly1305);
+
+        let first = initiator.encode_data(vec![1]).unwrap();
+        let second = initiator.encode_data(vec![2]).unwrap();
+
+        assert_eq!(responder.decode_data(second.clone()).unwrap(), vec![2]);
+        assert_eq!(responder.decode_data(first).unwrap(), vec![1]);
+
+        match responder.decode_data(second) {
+            Err(Error(ErrorKind::ReplayedFrame(1), _)) => (),
+            result => panic!("replayed frame was accepted: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn keys_can_be_rotated() {
+        let (mut initiator, mut responder) = pair(Algorithm::ChaCha20Poly1305);
+
+        let in_flight = initiator.encode_data(vec![1]).unwrap();
+        initiator.rotate_key(&OTHER_KEY);
+        let rotated = initiator.encode_data(vec![2]).unwrap();
+
+        // The responder has not rotated yet, so it cannot read the new frame.
+        assert!(responder.clone().decode_data(rotated.clone()).is_err());
+
+        responder.rotate_key(&OTHER_KEY);
+        assert_eq!(responder.epoch(), 1);
+        assert_eq!(responder.decode_data(rotated).unwrap(), vec![2]);
+        assert_eq!(responder.decode_data(in_flight).unwrap(), vec![1]);
+
+        let old = Encryption::new(Algorithm::ChaCha20Poly1305, &KEY, Side::Initiator).encode_data(vec![3]).unwrap();
+        responder.rotate_key_with(Algorithm::Aes256Gcm, &KEY);
+        assert_eq!(responder.algorithm(), Algorithm::Aes256Gcm);
+        assert!(responder.decode_data(old).is_err());
+    }
+
+    #[test]
+    fn can_be_used_in_a_pipeline() {
+        define_middleware_pipeline!(Secure {
+            rotate: RotateBytes,
+            encryption: Encryption
+        });
+
+        let mut sender = Secure {
+            rotate: RotateBytes::ROT13,
+            encryption: Encryption::new(Algorithm::ChaCha20Poly1305, &KEY, Side::Initiator),
+        };
+        let mut receiver = Secure {
+            rotate: RotateBytes::ROT13,
+            encryption: Encryption::new(Algorithm::ChaCha20Poly1305, &KEY, Side::Responder),
+        };
+
+        let data = b"hello world".to_vec();
+        let encoded = sender.encode_data(data.clone()).unwrap();
+        assert_eq!(receiver.decode_data(encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn headers_are_written_into_the_frame_headroom() {
+        let (mut sender, mut receiver) = pair(Algorithm::ChaCha20Poly1305);
+
+        let mut frame = Frame::with_headroom(HEADER_SIZE);
+        frame.extend_from_slice(b"hello");
+        sender.encode_frame(&mut frame).unwrap();
+        assert_eq!((frame.headroom(), frame.len()), (0, HEADER_SIZE + 5 + TAG_SIZE));
+
+        receiver.decode_frame(&mut frame).unwrap();
+        assert_eq!((&frame[..], frame.headroom()), (&b"hello"[..], HEADER_SIZE));
+    }
+
+    #[test]
+    fn padding_hides_the_size_of_encrypted_frames() {
+        use crate::wire::middleware::padding::Padding;
+
+        define_middleware_pipeline!(Padded {
+            padding: Padding,
+            encryption: Encryption
+        });
+
+        let mut sender = Padded {
+            padding: Padding::PowersOfTwo { min: 64 },
+            encryption: Encryption::new(Algorithm::Aes256Gcm, &KEY, Side::Initiator),
+        };
+        let mut receiver = Padded {
+            padding: Padding::PowersOfTwo { min: 64 },
+            encryption: Encryption::new(Algorithm::Aes256Gcm, &KEY, Side::Responder),
+        };
+
+        for data in [vec![], vec![1], vec![2; 50]] {
+            let encoded = sender.encode_data(data.clone()).unwrap();
+            assert_eq!(encoded.len(), HEADER_SIZE + 64 + TAG_SIZE);
+            assert_eq!(receiver.decode_data(encoded).unwrap(), data);
+        }
+    }
+}
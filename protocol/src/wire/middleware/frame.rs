@@ -0,0 +1,187 @@
+//! A buffer that middleware can transform in place.
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+
+/// The headroom that frames for outgoing packets start out with.
+///
+/// This is enough for the headers of all middleware in this crate
+/// combined, so that none of them have to move the data to prepend
+/// their header.
+pub const DEFAULT_HEADROOM: usize = 64;
+
+/// The data of a single packet as it passes through middleware.
+///
+/// A frame keeps unused room in front of its data, so that headers can
+/// be prepended without moving the data, and stripping a header only
+/// moves the start of the frame. Frames dereference to their data.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Frame
+{
+    buffer: Vec<u8>,
+    /// The index into the buffer where the data starts.
+    start: usize,
+}
+
+impl Frame
+{
+    /// Creates an empty frame with room to prepend some bytes.
+    pub fn with_headroom(headroom: usize) -> Self {
+        Frame { buffer: vec![0; headroom], start: headroom }
+    }
+
+    /// Creates a frame holding some data, without any headroom.
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        Frame { buffer: data, start: 0 }
+    }
+
+    /// Gets the number of bytes that can be prepended without moving
+    /// the data.
+    pub fn headroom(&self) -> usize { self.start }
+
+    /// Inserts bytes in front of the data.
+    ///
+    /// If there is not enough headroom, the data is moved along to
+    /// make room, leaving `DEFAULT_HEADROOM` bytes spare.
+    pub fn prepend(&mut self, bytes: &[u8]) {
+        if bytes.len() > self.start {
+            let extra = bytes.len() - self.start + DEFAULT_HEADROOM;
+            let mut buffer = vec![0; extra];
+            buffer.extend_from_slice(&self.buffer);
+            self.buffer = buffer;
+            self.start += extra;
+        }
+
+        self.start -= bytes.len();
+        self.buffer[self.start..self.start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Appends bytes to the end of the data.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Appends `count` zeros to the end of the data.
+    pub fn extend_zeros(&mut self, count: usize) {
+        self.buffer.resize(self.buffer.len() + count, 0);
+    }
+
+    /// Removes bytes from the front of the data.
+    ///
+    /// The removed bytes become headroom.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is larger than the data.
+    pub fn advance(&mut self, count: usize) {
+        assert!(count <= self.len(), "cannot advance past the end of a frame");
+        self.start += count;
+    }
+
+    /// Shortens the data, keeping the first `len` bytes.
+    pub fn truncate(&mut self, len: usize) {
+        self.buffer.truncate(self.start + len);
+    }
+
+    /// Replaces the data, discarding the headroom.
+    pub fn replace(&mut self, data: Vec<u8>) {
+        *self = Frame::from_vec(data);
+    }
+
+    /// Takes the data out of the frame, leaving it empty.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self).into_vec()
+    }
+
+    /// Converts the frame into its data.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.buffer.drain(..self.start);
+        self.buffer
+    }
+}
+
+impl Deref for Frame
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] { &self.buffer[self.start..] }
+}
+
+impl DerefMut for Frame
+{
+    fn deref_mut(&mut self) -> &mut [u8] { &mut self.buffer[self.start..] }
+}
+
+/// Writing to a frame appends to its data.
+impl io::Write for Frame
+{
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Frame, DEFAULT_HEADROOM};
+    use std::io::Write;
+
+    #[test]
+    fn headers_are_prepended_into_the_headroom() {
+        let mut frame = Frame::with_headroom(4);
+        frame.write_all(&[5, 6]).unwrap();
+
+        frame.prepend(&[3, 4]);
+        assert_eq!(frame.headroom(), 2);
+        frame.prepend(&[1, 2]);
+        assert_eq!(frame.headroom(), 0);
+
+        assert_eq!(&frame[..], &[1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn data_is_moved_when_out_of_headroom() {
+        let mut frame = Frame::from_vec(vec![3, 4]);
+        frame.prepend(&[1, 2]);
+
+        assert_eq!(&frame[..], &[1, 2, 3, 4]);
+        assert_eq!(frame.headroom(), DEFAULT_HEADROOM);
+    }
+
+    #[test]
+    fn headers_and_trailers_can_be_stripped() {
+        let mut frame = Frame::from_vec(vec![1, 2, 3, 4, 5]);
+        frame.advance(2);
+        frame.truncate(2);
+
+        assert_eq!(&frame[..], &[3, 4]);
+        assert_eq!(frame.headroom(), 2);
+        assert_eq!(frame.len(), 2);
+        assert_eq!(frame.into_vec(), vec![3, 4]);
+    }
+
+    #[test]
+    fn data_can_be_taken_and_replaced() {
+        let mut frame = Frame::with_headroom(8);
+        frame.extend_from_slice(&[1, 2]);
+
+        assert_eq!(frame.take(), vec![1, 2]);
+        assert!(frame.is_empty());
+
+        frame.replace(vec![3]);
+        assert_eq!((&frame[..], frame.headroom()), (&[3][..], 0));
+    }
+
+    #[test]
+    fn zeros_can_be_appended() {
+        let mut frame = Frame::with_headroom(2);
+        frame.extend_from_slice(&[1, 2]);
+        frame.extend_zeros(3);
+
+        assert_eq!(&frame[..], &[1, 2, 0, 0, 0]);
+        assert_eq!(frame.headroom(), 2);
+    }
+}
@@ -1,10 +1,22 @@
 //! A type safe `Parcel` data transformation pipeline.
 
 pub use self::pipeline::Pipeline;
+pub use self::context::{Context, Direction, Metadata};
+pub use self::dynamic::DynamicPipeline;
+pub use self::frame::Frame;
 
 #[macro_use] pub mod pipeline;
+#[cfg(feature = "middleware-authentication")] pub mod authentication;
+#[cfg(feature = "middleware-checksum")] pub mod checksum;
 #[cfg(feature = "middleware-compression")] pub mod compression;
+pub mod context;
+pub mod dynamic;
+#[cfg(feature = "middleware-encryption")] pub mod encryption;
+pub mod frame;
+pub mod padding;
+pub mod replay;
 pub mod rotate_bytes;
+#[cfg(feature = "middleware-compression")] pub mod stream_compression;
 
 use crate::Error;
 use std;
@@ -19,5 +31,54 @@ pub trait Middleware : std::fmt::Debug
     fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error>;
     /// Un-processes some data.
     fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error>;
+
+    /// Processes a frame in place.
+    ///
+    /// Middleware that can transform data without reallocating it
+    /// should override this, and the pipelines will use it instead of
+    /// `encode_data`. By default the data is taken out of the frame and
+    /// passed to `encode_data`.
+    fn encode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        let data = frame.take();
+        frame.replace(self.encode_data(data)?);
+        Ok(())
+    }
+
+    /// Un-processes a frame in place.
+    ///
+    /// By default the data is taken out of the frame and passed to
+    /// `decode_data`.
+    fn decode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        let data = frame.take();
+        frame.replace(self.decode_data(data)?);
+        Ok(())
+    }
+
+    /// Processes a frame in place, given information about its packet.
+    ///
+    /// Middleware that behaves differently depending on the packet
+    /// should override this. By default the context is ignored and
+    /// `encode_frame` is called.
+    fn encode_with_context(&mut self,
+                           frame: &mut Frame,
+                           context: &mut Context) -> Result<(), Error> {
+        let _ = context;
+        self.encode_frame(frame)
+    }
+
+    /// Un-processes a frame in place, given information about its packet.
+    ///
+    /// By default the context is ignored and `decode_frame` is called.
+    fn decode_with_context(&mut self,
+                           frame: &mut Frame,
+                           context: &mut Context) -> Result<(), Error> {
+        let _ = context;
+        self.decode_frame(frame)
+    }
+
+    /// Checks if the middleware currently leaves data unchanged.
+    ///
+    /// Pipelines may skip calling middleware that is a no-op.
+    fn is_noop(&self) -> bool { false }
 }
 
@@ -0,0 +1,220 @@
+//! A middleware for hiding the exact size of packets.
+//!
+//! Encryption hides what is in a packet, but not how large it is, and
+//! the size alone is often enough to tell which message was sent. This
+//! middleware pads every frame up to one of a few bucket sizes, so that
+//! all packets within a bucket look the same on the wire.
+//!
+//! Frames are prefixed by the length of the data as a 32-bit big endian
+//! integer, and padded with zeros. The zeros are checked when decoding.
+//!
+//! Padding must come before encryption in a pipeline, so that the
+//! padding is encrypted along with the data. Pipelines encode with their
+//! middleware in the order it is declared.
+
+use crate::{wire, wire::middleware::Frame, Error, ErrorKind};
+
+use byteorder::{BigEndian, ByteOrder};
+
+/// The size of the length prefix.
+pub const LENGTH_SIZE: usize = 4;
+
+/// Padding middleware.
+///
+/// Bucket sizes include the length prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Padding
+{
+    /// Frames should be left as they are.
+    Disabled,
+    /// Frames should be padded to the next power of two, and to at
+    /// least `min` bytes.
+    PowersOfTwo {
+        min: usize,
+    },
+    /// Frames should be padded to the smallest bucket that they fit in.
+    ///
+    /// Frames larger than all buckets are padded to a multiple of the
+    /// largest bucket.
+    Buckets(Vec<usize>),
+}
+
+impl Padding
+{
+    /// Gets the size that a frame of a certain size is padded to,
+    /// including the length prefix.
+    ///
+    /// Fails with `ErrorKind::PacketTooLarge` if the padded size does
+    /// not fit into a `usize`.
+    pub fn padded_size(&self, size: usize) -> Result<usize, Error> {
+        let too_large = |max_padded_size: usize| -> Error {
+            ErrorKind::PacketTooLarge(size, max_padded_size - LENGTH_SIZE).into()
+        };
+
+        match *self {
+            Padding::Disabled => Ok(size),
+            Padding::PowersOfTwo { min } => {
+                size.checked_add(LENGTH_SIZE)
+                    .and_then(|size| size.max(min).checked_next_power_of_two())
+                    .ok_or_else(|| too_large(1 << (usize::BITS - 1)))
+            },
+            Padding::Buckets(ref buckets) => {
+                let largest = buckets.iter().cloned().max().unwrap_or(0);
+                let size = match size.checked_add(LENGTH_SIZE) {
+                    Some(size) => size,
+                    None => return Err(too_large(usize::MAX)),
+                };
+                let smallest_fit = buckets.iter().cloned().filter(|&bucket| bucket >= size).min();
+
+                match smallest_fit {
+                    Some(bucket) => Ok(bucket),
+                    None if largest > 0 => size.div_ceil(largest).checked_mul(largest)
+                        .ok_or_else(|| too_large(usize::MAX / largest * largest)),
+                    None => Ok(size),
+                }
+            },
+        }
+    }
+}
+
+impl wire::Middleware for Padding
+{
+    fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.encode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut frame = Frame::from_vec(data);
+        self.decode_frame(&mut frame)?;
+        Ok(frame.into_vec())
+    }
+
+    fn encode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        if *self == Padding::Disabled {
+            return Ok(());
+        }
+
+        if frame.len() > u32::MAX as usize {
+            return Err(ErrorKind::PacketTooLarge(frame.len(), u32::MAX as usize).into());
+        }
+
+        let padded_size = self.padded_size(frame.len())?;
+        let padding = padded_size - LENGTH_SIZE - frame.len();
+
+        let mut length = [0; LENGTH_SIZE];
+        BigEndian::write_u32(&mut length, frame.len() as u32);
+        frame.prepend(&length);
+        frame.extend_zeros(padding);
+
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        if *self == Padding::Disabled {
+            return Ok(());
+        }
+
+        if frame.len() < LENGTH_SIZE {
+            return Err(ErrorKind::MalformedFrame("padded frame is missing its length").into());
+        }
+
+        let length = BigEndian::read_u32(&frame[..LENGTH_SIZE]) as usize;
+        if length > frame.len() - LENGTH_SIZE {
+            return Err(ErrorKind::MalformedFrame("padded frame is shorter than its length").into());
+        }
+
+        if frame[LENGTH_SIZE + length..].iter().any(|&byte| byte != 0) {
+            return Err(ErrorKind::MalformedFrame("padding is not all zeros").into());
+        }
+
+        frame.truncate(LENGTH_SIZE + length);
+        frame.advance(LENGTH_SIZE);
+        Ok(())
+    }
+
+    fn is_noop(&self) -> bool {
+        *self == Padding::Disabled
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use crate::wire::Middleware;
+
+    #[test]
+    fn frames_are_padded_to_powers_of_two() {
+        let mut padding = Padding::PowersOfTwo { min: 16 };
+
+        for &(size, padded_size) in &[(0, 16), (12, 16), (13, 32), (60, 64), (1000, 1024)] {
+            let data = vec![0xaa; size];
+            let frame = padding.encode_data(data.clone()).unwrap();
+
+            assert_eq!(frame.len(), padded_size);
+            assert_eq!(padding.decode_data(frame).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn frames_are_padded_to_buckets() {
+        let padding = Padding::Buckets(vec![256, 64, 1024]);
+
+        assert_eq!(padding.padded_size(0).unwrap(), 64);
+        assert_eq!(padding.padded_size(60).unwrap(), 64);
+        assert_eq!(padding.padded_size(61).unwrap(), 256);
+        assert_eq!(padding.padded_size(1020).unwrap(), 1024);
+        assert_eq!(padding.padded_size(1021).unwrap(), 2048);
+        assert_eq!(Padding::Buckets(vec![]).padded_size(10).unwrap(), 14);
+    }
+
+    #[test]
+    fn padded_sizes_that_overflow_are_rejected() {
+        let cases = [
+            (Padding::PowersOfTwo { min: 16 }, usize::MAX - 1),
+            (Padding::PowersOfTwo { min: 16 }, usize::MAX / 2 + 1),
+            (Padding::Buckets(vec![64]), usize::MAX - 1),
+            (Padding::Buckets(vec![]), usize::MAX - 1),
+            (Padding::Buckets(vec![1000]), usize::MAX - 100),
+        ];
+
+        for (padding, size) in cases.iter() {
+            match padding.padded_size(*size) {
+                Err(Error(ErrorKind::PacketTooLarge(..), _)) => (),
+                result => panic!("{:?} padded {} bytes to {:?}", padding, size, result),
+            }
+        }
+
+        let largest = usize::MAX / 2 + 1;
+        assert_eq!(Padding::PowersOfTwo { min: 16 }.padded_size(largest - LENGTH_SIZE).unwrap(), largest);
+    }
+
+    #[test]
+    fn invalid_padding_is_rejected() {
+        let mut padding = Padding::PowersOfTwo { min: 16 };
+        let frame = padding.encode_data(vec![1, 2, 3]).unwrap();
+
+        let mut nonzero = frame.clone();
+        *nonzero.last_mut().unwrap() = 1;
+        let mut too_long = frame.clone();
+        too_long[3] = 13;
+
+        for frame in [nonzero, too_long, vec![0, 0, 0]] {
+            match padding.decode_data(frame) {
+                Err(Error(ErrorKind::MalformedFrame(..), _)) => (),
+                result => panic!("invalid padding was accepted: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn disabled_padding_leaves_frames_alone() {
+        let mut padding = Padding::Disabled;
+
+        assert!(padding.is_noop());
+        assert_eq!(padding.encode_data(vec![1, 2, 3]).unwrap(), vec![1, 2, 3]);
+        assert_eq!(padding.decode_data(vec![1, 2, 3]).unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -1,6 +1,6 @@
 //! An ordered list of middleware that performs tested transformations.
 
-use crate::Error;
+use crate::{wire::middleware::{Context, Frame}, Error};
 use std;
 
 /// A middleware pipeline.
@@ -8,6 +8,51 @@ pub trait Pipeline : std::fmt::Debug
 {
     fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error>;
     fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error>;
+
+    /// Processes a frame in place.
+    ///
+    /// By default the data is taken out of the frame and passed to
+    /// `encode_data`.
+    fn encode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        let data = frame.take();
+        frame.replace(self.encode_data(data)?);
+        Ok(())
+    }
+
+    /// Un-processes a frame in place.
+    ///
+    /// By default the data is taken out of the frame and passed to
+    /// `decode_data`.
+    fn decode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        let data = frame.take();
+        frame.replace(self.decode_data(data)?);
+        Ok(())
+    }
+
+    /// Processes a frame in place, passing packet information to
+    /// every stage.
+    ///
+    /// By default the context is ignored and `encode_frame` is called.
+    fn encode_with_context(&mut self,
+                           frame: &mut Frame,
+                           context: &mut Context) -> Result<(), Error> {
+        let _ = context;
+        self.encode_frame(frame)
+    }
+
+    /// Un-processes a frame in place, passing packet information to
+    /// every stage.
+    ///
+    /// By default the context is ignored and `decode_frame` is called.
+    fn decode_with_context(&mut self,
+                           frame: &mut Frame,
+                           context: &mut Context) -> Result<(), Error> {
+        let _ = context;
+        self.decode_frame(frame)
+    }
+
+    /// Checks if every stage of the pipeline currently leaves data unchanged.
+    fn is_noop(&self) -> bool { false }
 }
 
 /// Creates an instance of the default middleware.
@@ -40,23 +85,71 @@ macro_rules! define_middleware_pipeline {
 
         impl $crate::wire::middleware::Pipeline for $ty
         {
-            #[allow(unused_mut)]
-            fn encode_data(&mut self, mut data: Vec<u8>)
+            fn encode_data(&mut self, data: Vec<u8>)
+                -> Result<Vec<u8>, $crate::Error> {
+                let mut frame = $crate::wire::middleware::Frame::from_vec(data);
+                self.encode_frame(&mut frame)?;
+
+                Ok(frame.into_vec())
+            }
+
+            fn decode_data(&mut self, data: Vec<u8>)
                 -> Result<Vec<u8>, $crate::Error> {
+                let mut frame = $crate::wire::middleware::Frame::from_vec(data);
+                self.decode_frame(&mut frame)?;
+
+                Ok(frame.into_vec())
+            }
+
+            #[allow(unused_variables)]
+            fn encode_frame(&mut self, frame: &mut $crate::wire::middleware::Frame)
+                -> Result<(), $crate::Error> {
+                #[allow(unused_imports)]
                 use $crate::wire::Middleware;
 
-                $( data = self.$mw_name.encode_data(data)?; )*
+                $( self.$mw_name.encode_frame(frame)?; )*
 
-                Ok(data)
+                Ok(())
             }
 
-            fn decode_data(&mut self, mut data: Vec<u8>)
-                -> Result<Vec<u8>, $crate::Error> {
+            fn decode_frame(&mut self, frame: &mut $crate::wire::middleware::Frame)
+                -> Result<(), $crate::Error> {
+                for middleware in self.middleware_mut() {
+                    middleware.decode_frame(frame)?;
+                }
+
+                Ok(())
+            }
+
+            #[allow(unused_variables)]
+            fn encode_with_context(&mut self,
+                                   frame: &mut $crate::wire::middleware::Frame,
+                                   context: &mut $crate::wire::middleware::Context)
+                -> Result<(), $crate::Error> {
+                #[allow(unused_imports)]
+                use $crate::wire::Middleware;
+
+                $( self.$mw_name.encode_with_context(frame, context)?; )*
+
+                Ok(())
+            }
+
+            fn decode_with_context(&mut self,
+                                   frame: &mut $crate::wire::middleware::Frame,
+                                   context: &mut $crate::wire::middleware::Context)
+                -> Result<(), $crate::Error> {
                 for middleware in self.middleware_mut() {
-                    data = middleware.decode_data(data)?;
+                    middleware.decode_with_context(frame, context)?;
                 }
 
-                Ok(data)
+                Ok(())
+            }
+
+            fn is_noop(&self) -> bool {
+                #[allow(unused_imports)]
+                use $crate::wire::Middleware;
+
+                true $( && self.$mw_name.is_noop() )*
             }
         }
     };
@@ -101,6 +194,7 @@ mod test
     {
         fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> { Ok(data) }
         fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> { Ok(data) }
+        fn is_noop(&self) -> bool { true }
     }
 
     #[test]
@@ -115,5 +209,24 @@ mod test
         assert_eq!(null_pipeline.encode_data(data.clone()).unwrap(), data.clone());
         assert_eq!(null_pipeline.decode_data(data.clone()).unwrap(), data.clone());
     }
+
+    define_middleware_pipeline!(Rotating {
+        compression: NullMiddleware,
+        rotate: wire::middleware::rotate_bytes::RotateBytes
+    });
+
+    #[test]
+    fn pipeline_is_noop_only_if_all_middleware_is() {
+        let mut pipeline = Rotating {
+            compression: NullMiddleware,
+            rotate: wire::middleware::rotate_bytes::RotateBytes { amount: 0 },
+        };
+
+        assert!(NullPipeline { encryption: NullMiddleware, compression: NullMiddleware }.is_noop());
+        assert!(pipeline.is_noop());
+
+        pipeline.rotate.amount = 1;
+        assert!(!pipeline.is_noop());
+    }
 }
 
@@ -0,0 +1,100 @@
+//! Detection of replayed frames.
+
+/// The number of sequence numbers behind the newest one that are tracked.
+pub const WINDOW_SIZE: u64 = 64;
+
+/// A sliding window over received sequence numbers.
+///
+/// Frames may arrive out of order by up to `WINDOW_SIZE` sequence
+/// numbers, which is needed on datagram links. Anything older than
+/// that, and anything that has already been seen, is rejected.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayWindow
+{
+    /// The highest sequence number seen, plus one.
+    ///
+    /// Zero if nothing has been received yet.
+    next: u64,
+    /// Bit `n` is set if sequence number `next - 1 - n` has been seen.
+    seen: u64,
+}
+
+impl ReplayWindow
+{
+    /// Creates a new window that has not seen any sequence numbers.
+    pub fn new() -> Self {
+        ReplayWindow::default()
+    }
+
+    /// Checks if a sequence number would be accepted, without marking
+    /// it as seen.
+    ///
+    /// Frames should be authenticated before they are marked as seen
+    /// with `accept`, so that forged frames cannot move the window.
+    pub fn check(&self, sequence: u64) -> bool {
+        if sequence >= self.next {
+            true
+        } else {
+            let age = self.next - 1 - sequence;
+            age < WINDOW_SIZE && self.seen & (1 << age) == 0
+        }
+    }
+
+    /// Marks a sequence number as seen.
+    ///
+    /// Returns `false` if it was a replay.
+    pub fn accept(&mut self, sequence: u64) -> bool {
+        if !self.check(sequence) {
+            return false;
+        }
+
+        if sequence >= self.next {
+            let shift = sequence - self.next + 1;
+            self.seen = if shift >= WINDOW_SIZE { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = sequence + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - sequence);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{ReplayWindow, WINDOW_SIZE};
+
+    #[test]
+    fn sequence_numbers_are_only_accepted_once() {
+        let mut window = ReplayWindow::new();
+
+        assert!(window.accept(0));
+        assert!(window.accept(1));
+        assert!(!window.accept(1));
+        assert!(!window.accept(0));
+    }
+
+    #[test]
+    fn reordered_sequence_numbers_are_accepted() {
+        let mut window = ReplayWindow::new();
+
+        assert!(window.accept(5));
+        assert!(window.accept(3));
+        assert!(window.accept(4));
+        assert!(!window.accept(3));
+        assert!(window.accept(0));
+    }
+
+    #[test]
+    fn old_sequence_numbers_are_rejected() {
+        let mut window = ReplayWindow::new();
+
+        assert!(window.accept(WINDOW_SIZE + 10));
+        assert!(!window.check(9));
+        assert!(window.check(11));
+        assert!(window.accept(1000));
+        assert!(!window.check(WINDOW_SIZE + 10));
+    }
+}
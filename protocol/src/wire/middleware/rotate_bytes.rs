@@ -1,6 +1,6 @@
 //! A fixed-offset based caesar cipher middleware.
 
-use crate::{wire, Error};
+use crate::{wire, wire::middleware::Frame, Error};
 
 use std::num::Wrapping;
 
@@ -21,12 +21,34 @@ impl RotateBytes {
 }
 
 impl wire::Middleware for RotateBytes {
-    fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
-        Ok(data.into_iter().map(|b| (Wrapping(b) + Wrapping(self.amount)).0).collect())
+    fn encode_data(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        rotate(&mut data, self.amount);
+        Ok(data)
     }
 
-    fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
-        Ok(data.into_iter().map(|b| (Wrapping(b) - Wrapping(self.amount)).0).collect())
+    fn decode_data(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        rotate(&mut data, self.amount.wrapping_neg());
+        Ok(data)
+    }
+
+    fn encode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        rotate(frame, self.amount);
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, frame: &mut Frame) -> Result<(), Error> {
+        rotate(frame, self.amount.wrapping_neg());
+        Ok(())
+    }
+
+    fn is_noop(&self) -> bool {
+        self.amount == 0
+    }
+}
+
+fn rotate(bytes: &mut [u8], amount: u8) {
+    for byte in bytes.iter_mut() {
+        *byte = (Wrapping(*byte) + Wrapping(amount)).0;
     }
 }
 
@@ -0,0 +1,350 @@
+//! A middleware for compressing data with a context shared across packets.
+//!
+//! Requires the `middleware-compression` crate feature to be enabled, and
+//! `middleware-compression-zstd` for zstd.
+//!
+//! Each direction keeps one compression stream open for the lifetime of
+//! the connection, and every packet is flushed with a sync flush. Small,
+//! repetitive packets compress far better this way than one at a time,
+//! because later packets can refer back to data in earlier ones.
+//!
+//! Because the two ends of a stream must stay in sync, every frame has
+//! to be decoded exactly once and in the order it was encoded. This
+//! makes the middleware unsuitable for unreliable or unordered datagrams.
+//!
+//! The context is shared between the packets passing through one
+//! middleware, never between middleware. Clones start out with fresh
+//! streams.
+
+use crate::{wire, wire::middleware::compression::{Level, DEFAULT_MAX_DECOMPRESSED_SIZE}, Error, ErrorKind};
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+
+use std::fmt;
+#[cfg(feature = "middleware-compression-zstd")]
+use std::sync::Arc;
+
+/// The smallest amount of spare room given to the compressor each pass.
+const MIN_CHUNK_SIZE: usize = 256;
+
+/// Streaming compression middleware.
+///
+/// Cloning the middleware does not copy its compression streams. The
+/// clone starts with fresh streams, as if it had just been created with
+/// the same settings, so it cannot decode frames produced by the original
+/// once the original has encoded anything.
+pub struct StreamCompression
+{
+    algorithm: Algorithm,
+    encoder: Encoder,
+    decoder: Decoder,
+    /// The largest decompressed frame that will be accepted.
+    pub max_decompressed_size: usize,
+}
+
+/// The algorithm and settings that a stream was created with.
+#[derive(Clone, Debug)]
+enum Algorithm
+{
+    Deflate(Level),
+    #[cfg(feature = "middleware-compression-zstd")]
+    Zstd(Level, Option<Arc<[u8]>>),
+}
+
+enum Encoder
+{
+    Deflate(Compress),
+    #[cfg(feature = "middleware-compression-zstd")]
+    Zstd(zstd::stream::raw::Encoder<'static>),
+}
+
+enum Decoder
+{
+    Deflate(Decompress),
+    #[cfg(feature = "middleware-compression-zstd")]
+    Zstd(zstd::stream::raw::Decoder<'static>),
+}
+
+impl StreamCompression
+{
+    /// Creates a middleware using raw deflate streams.
+    pub fn deflate(level: Level) -> Self {
+        StreamCompression::new(Algorithm::Deflate(level)).expect("deflate streams cannot fail to initialize")
+    }
+
+    /// Creates a middleware using zstd streams.
+    #[cfg(feature = "middleware-compression-zstd")]
+    pub fn zstd(level: Level) -> Result<Self, Error> {
+        StreamCompression::new(Algorithm::Zstd(level, None))
+    }
+
+    /// Creates a middleware using zstd streams primed with a dictionary.
+    ///
+    /// Both ends must use the same dictionary. Dictionaries can be
+    /// trained from sample packets with `zstd::dict::from_samples`.
+    #[cfg(feature = "middleware-compression-zstd")]
+    pub fn zstd_with_dictionary(level: Level,
+                                dictionary: Vec<u8>) -> Result<Self, Error> {
+        StreamCompression::new(Algorithm::Zstd(level, Some(dictionary.into())))
+    }
+
+    fn new(algorithm: Algorithm) -> Result<Self, Error> {
+        let (encoder, decoder) = match algorithm {
+            Algorithm::Deflate(level) => (
+                Encoder::Deflate(Compress::new(level.flate2(), false)),
+                Decoder::Deflate(Decompress::new(false)),
+            ),
+            #[cfg(feature = "middleware-compression-zstd")]
+            Algorithm::Zstd(level, ref dictionary) => {
+                let dictionary = dictionary.as_ref().map_or(&[][..], |dictionary| &dictionary[..]);
+                (
+                    Encoder::Zstd(zstd::stream::raw::Encoder::with_dictionary(level.zstd(), dictionary)?),
+                    Decoder::Zstd(zstd::stream::raw::Decoder::with_dictionary(dictionary)?),
+                )
+            },
+        };
+
+        Ok(StreamCompression {
+            algorithm,
+            encoder,
+            decoder,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        })
+    }
+}
+
+impl wire::Middleware for StreamCompression
+{
+    fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::with_capacity(data.len() / 2 + MIN_CHUNK_SIZE);
+
+        match self.encoder {
+            Encoder::Deflate(ref mut compress) => {
+                let start = compress.total_in();
+
+                // A sync flush is complete once all input has been
+                // consumed and the compressor did not fill the output.
+                loop {
+                    reserve(&mut output);
+                    let consumed = (compress.total_in() - start) as usize;
+                    compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                            .map_err(std::io::Error::from)?;
+
+                    if (compress.total_in() - start) as usize == data.len() && output.len() < output.capacity() {
+                        break;
+                    }
+                }
+            },
+            #[cfg(feature = "middleware-compression-zstd")]
+            Encoder::Zstd(ref mut encoder) => {
+                use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+                let mut input = InBuffer::around(&data);
+                while input.pos < data.len() {
+                    reserve(&mut output);
+                    let position = output.len();
+                    encoder.run(&mut input, &mut OutBuffer::around_pos(&mut output, position))?;
+                }
+
+                loop {
+                    reserve(&mut output);
+                    let position = output.len();
+                    if encoder.flush(&mut OutBuffer::around_pos(&mut output, position))? == 0 { break; }
+                }
+            },
+        }
+
+        Ok(output)
+    }
+
+    fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::with_capacity(data.len() * 2 + MIN_CHUNK_SIZE);
+
+        match self.decoder {
+            Decoder::Deflate(ref mut decompress) => {
+                let start = decompress.total_in();
+
+                loop {
+                    reserve(&mut output);
+                    let (consumed, produced) = ((decompress.total_in() - start) as usize, output.len());
+                    let status = decompress.decompress_vec(&data[consumed..], &mut output, FlushDecompress::Sync)
+                                           .map_err(std::io::Error::from)?;
+
+                    check_size(output.len(), self.max_decompressed_size)?;
+
+                    let done = (decompress.total_in() - start) as usize == data.len() && output.len() < output.capacity();
+                    if done || status == Status::StreamEnd { break; }
+                    check_progress(consumed != (decompress.total_in() - start) as usize || produced != output.len())?;
+                }
+            },
+            #[cfg(feature = "middleware-compression-zstd")]
+            Decoder::Zstd(ref mut decoder) => {
+                use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+                let mut input = InBuffer::around(&data);
+                loop {
+                    reserve(&mut output);
+                    let (consumed, produced) = (input.pos, output.len());
+                    decoder.run(&mut input, &mut OutBuffer::around_pos(&mut output, produced))?;
+
+                    check_size(output.len(), self.max_decompressed_size)?;
+
+                    if input.pos == data.len() && output.len() < output.capacity() { break; }
+                    check_progress(consumed != input.pos || produced != output.len())?;
+                }
+            },
+        }
+
+        Ok(output)
+    }
+}
+
+impl Clone for StreamCompression
+{
+    fn clone(&self) -> Self {
+        let mut clone = StreamCompression::new(self.algorithm.clone())
+            .expect("streams that were created once can be created again");
+        clone.max_decompressed_size = self.max_decompressed_size;
+        clone
+    }
+}
+
+impl fmt::Debug for StreamCompression
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("StreamCompression")
+           .field("algorithm", &self.algorithm)
+           .field("max_decompressed_size", &self.max_decompressed_size)
+           .finish()
+    }
+}
+
+/// Makes sure that there is spare room at the end of a buffer.
+fn reserve(buffer: &mut Vec<u8>) {
+    if buffer.capacity() - buffer.len() < MIN_CHUNK_SIZE {
+        buffer.reserve(buffer.capacity().max(MIN_CHUNK_SIZE));
+    }
+}
+
+fn check_size(size: usize, limit: usize) -> Result<(), Error> {
+    if size > limit {
+        Err(ErrorKind::PacketTooLarge(size, limit).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Fails if the decompressor stopped making progress on a frame,
+/// which would otherwise loop forever.
+fn check_progress(made_progress: bool) -> Result<(), Error> {
+    if made_progress {
+        Ok(())
+    } else {
+        Err(ErrorKind::MalformedFrame("compressed data could not be decompressed").into())
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use crate::wire::Middleware;
+
+    fn streams() -> Vec<StreamCompression> {
+        vec![
+            StreamCompression::deflate(Level::Default),
+            #[cfg(feature = "middleware-compression-zstd")] StreamCompression::zstd(Level::Default).unwrap(),
+        ]
+    }
+
+    fn packet(id: u32) -> Vec<u8> {
+        format!("{{\"type\":\"position\",\"entity\":{},\"x\":1.5,\"y\":-2.25}}", id).into_bytes()
+    }
+
+    #[test]
+    fn packets_are_compressed_and_decompressed_in_order() {
+        for mut sender in streams() {
+            let mut receiver = sender.clone();
+
+            for id in 0..100 {
+                let frame = sender.encode_data(packet(id)).unwrap();
+                assert_eq!(receiver.decode_data(frame).unwrap(), packet(id), "{:?}", sender);
+            }
+
+            assert_eq!(receiver.decode_data(sender.encode_data(vec![]).unwrap()).unwrap(), vec![]);
+        }
+    }
+
+    #[test]
+    fn later_packets_compress_better_than_the_first() {
+        for mut sender in streams() {
+            let first = sender.encode_data(packet(1)).unwrap();
+            let later = sender.encode_data(packet(2)).unwrap();
+
+            assert!(later.len() * 2 < first.len(), "{:?}: {} vs {} bytes", sender, later.len(), first.len());
+        }
+    }
+
+    #[test]
+    fn large_packets_are_read_back() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8 ^ (i / 1000) as u8).collect();
+
+        for mut sender in streams() {
+            let mut receiver = sender.clone();
+
+            let frame = sender.encode_data(data.clone()).unwrap();
+            assert_eq!(receiver.decode_data(frame).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn clones_start_fresh_streams() {
+        for mut sender in streams() {
+            let first = sender.encode_data(packet(1)).unwrap();
+            let mut clone = sender.clone();
+
+            assert_eq!(clone.encode_data(packet(1)).unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn clones_cannot_decode_frames_from_the_original() {
+        for mut sender in streams() {
+            let mut receiver = sender.clone();
+            receiver.decode_data(sender.encode_data(packet(1)).unwrap()).unwrap();
+
+            let mut clone = receiver.clone();
+            let frame = sender.encode_data(packet(2)).unwrap();
+
+            assert_ne!(clone.decode_data(frame.clone()).ok(), Some(packet(2)), "{:?}", sender);
+            assert_eq!(receiver.decode_data(frame).unwrap(), packet(2));
+        }
+    }
+
+    #[test]
+    fn decompressed_size_is_limited() {
+        for mut sender in streams() {
+            let mut receiver = sender.clone();
+            receiver.max_decompressed_size = 1000;
+
+            match receiver.decode_data(sender.encode_data(vec![0; 100_000]).unwrap()) {
+                Err(Error(ErrorKind::PacketTooLarge(..), _)) => (),
+                result => panic!("{:?} accepted a frame over the limit: {:?}", sender, result.map(|data| data.len())),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "middleware-compression-zstd")]
+    fn zstd_dictionaries_are_used() {
+        let samples: Vec<Vec<u8>> = (0..1000).map(packet).collect();
+        let dictionary = zstd::dict::from_samples(&samples, 4096).unwrap();
+
+        let mut plain = StreamCompression::zstd(Level::Default).unwrap();
+        let mut sender = StreamCompression::zstd_with_dictionary(Level::Default, dictionary.clone()).unwrap();
+        let mut receiver = StreamCompression::zstd_with_dictionary(Level::Default, dictionary).unwrap();
+
+        let frame = sender.encode_data(packet(5000)).unwrap();
+        assert!(frame.len() < plain.encode_data(packet(5000)).unwrap().len());
+        assert_eq!(receiver.decode_data(frame).unwrap(), packet(5000));
+    }
+}
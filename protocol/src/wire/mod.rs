@@ -4,12 +4,13 @@
 //! parcels.
 
 pub use self::middleware::Middleware;
-pub use self::reader::Reader;
+pub use self::reader::{Drain, Reader};
 
 /// Datagram-based over the wire communication.
 pub mod dgram;
 mod reader;
 #[macro_use] pub mod middleware;
+pub mod rate_limit;
 /// Stream-based over the wire communication.
 pub mod stream;
 
@@ -0,0 +1,323 @@
+//! Token bucket rate limiting for outgoing packets.
+//!
+//! Sending a frame requires at least one token in every bucket, and
+//! the frame is then charged its full size once it has been encoded.
+//! A large frame can therefore briefly take the byte bucket below zero,
+//! delaying the frames after it. This way the middleware never encodes
+//! a frame that is not sent, which would desynchronize stateful
+//! middleware such as stream compression.
+//!
+//! # Example
+//!
+//! ```
+//! use protocol::wire::rate_limit::{ManualClock, Mode, RateLimit, RateLimiter};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! let clock = Arc::new(ManualClock::new());
+//! let limit = RateLimit { packets_per_second: Some(10), ..RateLimit::default() };
+//! let mut limiter = RateLimiter::with_clock(limit, Mode::Reject, clock.clone());
+//!
+//! // The bucket starts out full, with a burst's worth of tokens.
+//! assert!(limiter.acquire().is_ok());
+//! limiter.consume(100);
+//! assert!(limiter.acquire().is_err());
+//!
+//! clock.advance(Duration::from_millis(100));
+//! assert!(limiter.acquire().is_ok());
+//! ```
+
+use crate::{Error, ErrorKind};
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The default amount of sending that can happen at once.
+pub const DEFAULT_BURST: Duration = Duration::from_millis(100);
+
+/// A source of time for rate limiters.
+pub trait Clock : fmt::Debug + Send + Sync
+{
+    /// Gets the current time.
+    fn now(&self) -> Instant;
+
+    /// Waits for a duration to pass.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The system clock.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+/// A clock that only moves when told to, for deterministic tests.
+///
+/// Sleeping advances the clock instead of waiting.
+#[derive(Debug)]
+pub struct ManualClock
+{
+    now: Mutex<Instant>,
+}
+
+/// The rates that sending is limited to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit
+{
+    /// The number of bytes that can be sent per second, including
+    /// framing.
+    pub bytes_per_second: Option<u64>,
+    /// The number of frames that can be sent per second.
+    pub packets_per_second: Option<u64>,
+    /// How much sending can happen at once after a quiet period.
+    ///
+    /// The buckets hold this long's worth of tokens, and always enough
+    /// for at least one frame.
+    pub burst: Duration,
+}
+
+/// What to do when sending would exceed the rate limit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode
+{
+    /// Wait until there are enough tokens.
+    Block,
+    /// Fail with `ErrorKind::RateLimitExceeded`.
+    Reject,
+}
+
+/// A token bucket rate limiter.
+#[derive(Clone, Debug)]
+pub struct RateLimiter
+{
+    limit: RateLimit,
+    mode: Mode,
+    clock: Arc<dyn Clock>,
+    bytes: Option<Bucket>,
+    packets: Option<Bucket>,
+    refilled_at: Instant,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Bucket
+{
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+}
+
+impl RateLimiter
+{
+    /// Creates a new rate limiter using the system clock.
+    pub fn new(limit: RateLimit,
+               mode: Mode) -> Self {
+        RateLimiter::with_clock(limit, mode, Arc::new(SystemClock))
+    }
+
+    /// Creates a new rate limiter using a custom clock.
+    pub fn with_clock(limit: RateLimit,
+                      mode: Mode,
+                      clock: Arc<dyn Clock>) -> Self {
+        let burst = limit.burst.as_secs_f64();
+
+        RateLimiter {
+            limit,
+            mode,
+            bytes: limit.bytes_per_second.map(|rate| Bucket::new(rate, burst)),
+            packets: limit.packets_per_second.map(|rate| Bucket::new(rate, burst)),
+            refilled_at: clock.now(),
+            clock,
+        }
+    }
+
+    /// Gets the rates that sending is limited to.
+    pub fn limit(&self) -> &RateLimit { &self.limit }
+
+    /// Gets what happens when sending would exceed the rate limit.
+    pub fn mode(&self) -> Mode { self.mode }
+
+    /// Gets how long it is until a frame can be sent.
+    pub fn wait_time(&mut self) -> Duration {
+        self.refill();
+
+        self.bytes.iter().chain(self.packets.iter())
+            .map(Bucket::wait_time)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Makes sure that a frame can be sent.
+    ///
+    /// Blocks until it can, or fails with `ErrorKind::RateLimitExceeded`,
+    /// depending on the mode.
+    pub fn acquire(&mut self) -> Result<(), Error> {
+        loop {
+            let wait = self.wait_time();
+
+            if wait == Duration::from_secs(0) {
+                return Ok(());
+            }
+
+            match self.mode {
+                Mode::Block => self.clock.sleep(wait),
+                Mode::Reject => return Err(ErrorKind::RateLimitExceeded(wait).into()),
+            }
+        }
+    }
+
+    /// Charges the limiter for a frame that is being sent.
+    pub fn consume(&mut self, size: usize) {
+        self.refill();
+
+        if let Some(ref mut bytes) = self.bytes { bytes.tokens -= size as f64; }
+        if let Some(ref mut packets) = self.packets { packets.tokens -= 1.0; }
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.refilled_at = now;
+
+        for bucket in self.bytes.iter_mut().chain(self.packets.iter_mut()) {
+            bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.capacity);
+        }
+    }
+}
+
+impl Bucket
+{
+    fn new(rate: u64, burst: f64) -> Self {
+        let rate = rate as f64;
+        let capacity = (rate * burst).max(1.0);
+
+        Bucket { tokens: capacity, capacity, rate }
+    }
+
+    /// Gets how long it is until the bucket has a whole token.
+    fn wait_time(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::from_secs(0)
+        } else if self.rate > 0.0 {
+            Duration::try_from_secs_f64((1.0 - self.tokens) / self.rate).unwrap_or(Duration::MAX)
+        } else {
+            // Nothing can ever be sent.
+            Duration::MAX
+        }
+    }
+}
+
+impl Clock for SystemClock
+{
+    fn now(&self) -> Instant { Instant::now() }
+
+    fn sleep(&self, duration: Duration) { std::thread::sleep(duration) }
+}
+
+impl ManualClock
+{
+    /// Creates a new clock, starting at the current time.
+    pub fn new() -> Self {
+        ManualClock { now: Mutex::new(Instant::now()) }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock
+{
+    fn default() -> Self { ManualClock::new() }
+}
+
+impl Clock for ManualClock
+{
+    fn now(&self) -> Instant { *self.now.lock().unwrap() }
+
+    fn sleep(&self, duration: Duration) { self.advance(duration) }
+}
+
+impl Default for RateLimit
+{
+    fn default() -> Self {
+        RateLimit { bytes_per_second: None, packets_per_second: None, burst: DEFAULT_BURST }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn limiter(limit: RateLimit, mode: Mode) -> (RateLimiter, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        (RateLimiter::with_clock(limit, mode, clock.clone()), clock)
+    }
+
+    #[test]
+    fn bytes_are_limited() {
+        let limit = RateLimit { bytes_per_second: Some(1000), ..RateLimit::default() };
+        let (mut limiter, clock) = limiter(limit, Mode::Reject);
+
+        // A burst of 100 bytes, plus the frame that goes over it.
+        for _ in 0..3 {
+            limiter.acquire().unwrap();
+            limiter.consume(40);
+        }
+
+        match limiter.acquire() {
+            Err(Error(ErrorKind::RateLimitExceeded(wait), _)) => {
+                assert!(wait > Duration::from_millis(20) && wait < Duration::from_millis(22), "{:?}", wait);
+            },
+            result => panic!("rate limit was not enforced: {:?}", result),
+        }
+
+        clock.advance(Duration::from_millis(22));
+        assert!(limiter.acquire().is_ok());
+    }
+
+    #[test]
+    fn packets_are_limited() {
+        let limit = RateLimit { packets_per_second: Some(100), burst: Duration::from_millis(50), ..RateLimit::default() };
+        let (mut limiter, clock) = limiter(limit, Mode::Reject);
+
+        for _ in 0..5 {
+            limiter.acquire().unwrap();
+            limiter.consume(1_000_000);
+        }
+        assert!(limiter.acquire().is_err());
+
+        // Tokens do not build up past the burst size.
+        clock.advance(Duration::from_secs(10));
+        for _ in 0..5 {
+            limiter.acquire().unwrap();
+            limiter.consume(1);
+        }
+        assert!(limiter.acquire().is_err());
+    }
+
+    #[test]
+    fn blocking_waits_for_tokens() {
+        let limit = RateLimit { packets_per_second: Some(10), burst: Duration::from_secs(0), ..RateLimit::default() };
+        let (mut limiter, clock) = limiter(limit, Mode::Block);
+        let start = clock.now();
+
+        for _ in 0..11 {
+            limiter.acquire().unwrap();
+            limiter.consume(1);
+        }
+
+        let waited = clock.now() - start;
+        assert!(waited >= Duration::from_secs(1) && waited < Duration::from_millis(1001), "{:?}", waited);
+    }
+
+    #[test]
+    fn unlimited_limiters_never_wait() {
+        let (mut limiter, _) = limiter(RateLimit::default(), Mode::Reject);
+
+        for _ in 0..1000 {
+            limiter.acquire().unwrap();
+            limiter.consume(usize::MAX);
+        }
+    }
+}
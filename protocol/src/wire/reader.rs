@@ -1,6 +1,7 @@
 use crate::{Error, ErrorKind, Parcel, Settings};
 use std::io;
 use std::io::prelude::*;
+use std::marker::PhantomData;
 
 /// A receive buffer that waits until enough data is ready
 /// and then returns the parsed parcels.
@@ -42,20 +43,36 @@ use std::io::prelude::*;
 /// reader.write(&[0x00]).unwrap();
 /// assert_eq!(Some(0xff000000), reader.poll::<u32>(&settings).unwrap());
 /// ```
+///
+/// # Recovering from errors
+///
+/// Bytes that fail to parse are left in the receive buffer, so polling
+/// again will fail again. Protocols that mark the start of every parcel
+/// with a magic value can use `skip_to` to find the next parcel.
 #[derive(Debug)]
 pub struct Reader {
     /// The internal receive buffer.
     ///
     /// Contains all bytes that have been received but not yet parsed
-    /// into a packet.
+    /// into a packet, starting at `start`.
     receive_buffer: Vec<u8>,
+    /// The index of the first byte that has not been parsed yet.
+    ///
+    /// Parsed bytes are only removed from the front of the buffer once
+    /// they make up at least half of it, so that the pending bytes are
+    /// moved at most a constant number of times on average.
+    start: usize,
 }
 
+/// The number of parsed bytes that are kept around before compacting.
+const MIN_COMPACT_SIZE: usize = 4096;
+
 impl Reader {
     /// Creates a new parcel reader.
     pub fn new() -> Self {
         Reader {
             receive_buffer: Vec::new(),
+            start: 0,
         }
     }
 
@@ -68,17 +85,20 @@ impl Reader {
     /// from the stream.
     ///
     /// Returns `Err(e)` on error.
+    ///
+    /// The value is parsed directly from the receive buffer, without
+    /// copying it.
     pub fn poll<P>(&mut self,
                    settings: &Settings)
         -> Result<Option<P>, Error>
         where P: Parcel {
-        let mut cursor = io::Cursor::new(self.receive_buffer.clone());
+        let mut cursor = io::Cursor::new(&self.receive_buffer[self.start..]);
 
         match Parcel::read(&mut cursor, settings) {
             Ok(value) => {
-                // Remove the interpreted bytes from the receive buffer.
+                // Mark the interpreted bytes as parsed.
                 let bytes_read = cursor.position() as usize;
-                self.receive_buffer.drain(0..bytes_read);
+                self.consume(bytes_read);
 
                 Ok(Some(value))
             },
@@ -97,11 +117,141 @@ impl Reader {
             },
         }
     }
+
+    /// Returns an iterator over all complete values in the receive buffer.
+    ///
+    /// The iterator stops once more data must be received, or after
+    /// yielding an error.
+    ///
+    /// ```
+    /// use std::io::Write;
+    ///
+    /// let mut reader = protocol::wire::Reader::new();
+    /// let settings = protocol::Settings::default();
+    ///
+    /// reader.write(&[1, 2, 3]).unwrap();
+    /// let values: Result<Vec<u8>, _> = reader.drain::<u8>(&settings).collect();
+    /// assert_eq!(values.unwrap(), vec![1, 2, 3]);
+    /// ```
+    pub fn drain<'a, P>(&'a mut self,
+                        settings: &'a Settings) -> Drain<'a, P>
+        where P: Parcel {
+        Drain { reader: self, settings, done: false, _a: PhantomData }
+    }
+
+    /// Gets the bytes that have been received but not yet parsed.
+    pub fn pending(&self) -> &[u8] {
+        &self.receive_buffer[self.start..]
+    }
+
+    /// Gets the number of bytes that have been received but not yet
+    /// parsed.
+    pub fn pending_len(&self) -> usize {
+        self.receive_buffer.len() - self.start
+    }
+
+    /// Discards pending bytes from the front of the receive buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is larger than the number of pending bytes.
+    pub fn skip(&mut self, count: usize) {
+        assert!(count <= self.pending_len(), "cannot skip more bytes than are pending");
+        self.consume(count);
+    }
+
+    /// Discards pending bytes up to the next occurrence of a magic value.
+    ///
+    /// Returns `true` if the magic value was found, in which case it is
+    /// left at the front of the receive buffer. Otherwise everything is
+    /// discarded except for a trailing partial match, and more data
+    /// must be received before trying again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the magic value is empty.
+    pub fn skip_to(&mut self, magic: &[u8]) -> bool {
+        assert!(!magic.is_empty(), "magic value must not be empty");
+
+        let pending = self.pending();
+        match pending.windows(magic.len()).position(|window| window == magic) {
+            Some(position) => {
+                self.consume(position);
+                true
+            },
+            None => {
+                // The end of the buffer may be the start of the value.
+                let partial = (1..magic.len()).rev()
+                    .find(|&size| size <= pending.len() && pending.ends_with(&magic[..size]))
+                    .unwrap_or(0);
+
+                let count = pending.len() - partial;
+                self.consume(count);
+                false
+            },
+        }
+    }
+
+    /// Marks bytes at the front of the receive buffer as parsed.
+    fn consume(&mut self, count: usize) {
+        self.start += count;
+
+        if self.start == self.receive_buffer.len() {
+            self.receive_buffer.clear();
+            self.start = 0;
+        }
+    }
+
+    /// Removes parsed bytes from the front of the receive buffer, if
+    /// they are worth moving the pending bytes for.
+    fn compact(&mut self) {
+        if self.start >= MIN_COMPACT_SIZE && self.start * 2 >= self.receive_buffer.len() {
+            self.receive_buffer.drain(..self.start);
+            self.start = 0;
+        }
+    }
+}
+
+/// An iterator over the complete values in a `Reader`.
+///
+/// Created by `Reader::drain`.
+#[derive(Debug)]
+pub struct Drain<'a, P: Parcel>
+{
+    reader: &'a mut Reader,
+    settings: &'a Settings,
+    done: bool,
+    _a: PhantomData<P>,
+}
+
+impl<'a, P> Iterator for Drain<'a, P>
+    where P: Parcel
+{
+    type Item = Result<P, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.reader.poll(self.settings) {
+            Ok(Some(value)) => Some(Ok(value)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
 }
 
 impl Write for Reader {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.receive_buffer.extend(buf);
+        self.compact();
+        self.receive_buffer.extend_from_slice(buf);
         Ok(buf.len())
     }
 
@@ -114,3 +264,89 @@ impl Default for Reader {
     }
 }
 
+#[cfg(test)]
+mod test
+{
+    use super::{Reader, MIN_COMPACT_SIZE};
+    use crate::Settings;
+    use std::io::Write;
+
+    #[test]
+    fn parsed_bytes_are_compacted_away() {
+        let settings = Settings::default();
+        let mut reader = Reader::new();
+
+        for i in 0..(MIN_COMPACT_SIZE as u32) {
+            reader.write_all(&i.to_be_bytes()).unwrap();
+        }
+        // Start the next value, so that the buffer is never emptied.
+        reader.write_all(&[0xff]).unwrap();
+
+        for i in 0..(MIN_COMPACT_SIZE as u32) {
+            assert_eq!(reader.poll::<u32>(&settings).unwrap(), Some(i));
+        }
+        assert_eq!(reader.poll::<u32>(&settings).unwrap(), None);
+
+        reader.write_all(&[0, 0, 0]).unwrap();
+        assert_eq!((reader.start, reader.receive_buffer.len()), (0, 4));
+        assert_eq!(reader.poll::<u32>(&settings).unwrap(), Some(0xff000000));
+    }
+
+    #[test]
+    fn large_partial_values_are_read_back() {
+        let settings = Settings::default();
+        let mut reader = Reader::new();
+
+        let mut data = Vec::new();
+        crate::Parcel::write(&vec![7u8; 20_000], &mut data, &settings).unwrap();
+
+        for chunk in data.chunks(1000) {
+            assert_eq!(reader.poll::<Vec<u8>>(&settings).unwrap(), None);
+            reader.write_all(chunk).unwrap();
+        }
+
+        assert_eq!(reader.poll::<Vec<u8>>(&settings).unwrap(), Some(vec![7; 20_000]));
+        assert_eq!(reader.receive_buffer.len(), 0);
+    }
+
+    #[test]
+    fn drain_stops_at_incomplete_values_and_errors() {
+        let settings = Settings::default();
+        let mut reader = Reader::new();
+
+        reader.write_all(&[0, 1, 0, 2, 0]).unwrap();
+        let values: Vec<u16> = reader.drain(&settings).map(Result::unwrap).collect();
+        assert_eq!((values, reader.pending()), (vec![1, 2], &[0][..]));
+
+        // A string that is not UTF-8 is followed by a valid one.
+        reader.skip(1);
+        reader.write_all(&[0, 0, 0, 1, 0xff, 0, 0, 0, 1, b'a']).unwrap();
+        let results: Vec<_> = reader.drain::<String>(&settings).collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+        assert_eq!(reader.pending_len(), 10);
+
+        reader.skip(5);
+        let values: Vec<String> = reader.drain(&settings).map(Result::unwrap).collect();
+        assert_eq!(values, vec!["a".to_owned()]);
+    }
+
+    #[test]
+    fn can_resync_on_a_magic_value() {
+        const MAGIC: &[u8] = &[0xca, 0xfe];
+        let mut reader = Reader::new();
+
+        reader.write_all(&[1, 2, 0xca, 3, 0xca]).unwrap();
+        assert!(!reader.skip_to(MAGIC));
+        assert_eq!(reader.pending(), &[0xca]);
+
+        reader.write_all(&[0xfe, 9]).unwrap();
+        assert!(reader.skip_to(MAGIC));
+        assert_eq!(reader.pending(), &[0xca, 0xfe, 9]);
+
+        reader.skip(MAGIC.len());
+        assert_eq!(reader.poll::<u8>(&Settings::default()).unwrap(), Some(9));
+        assert_eq!(reader.pending_len(), 0);
+        assert!(!reader.skip_to(MAGIC));
+    }
+}
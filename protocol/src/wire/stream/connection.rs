@@ -1,9 +1,40 @@
-use crate::{Parcel, Error, Settings};
-use crate::wire::stream::{Transport, transport};
-use crate::wire::middleware;
+use crate::{Parcel, Error, ErrorKind, Settings};
+use crate::wire::rate_limit::RateLimiter;
+use crate::wire::stream::{queue::{Priority, Reassembler, SendQueue}, transport::{self, FrameKind, simple::PacketSize}, Transport};
+use crate::wire::middleware::{self, frame, Context, Frame, Metadata};
 
 use std::io::prelude::*;
-use std::io::Cursor;
+use std::io::{self, Cursor};
+use std::time::{Duration, Instant};
+
+/// The default number of buffered bytes at which a batch is written.
+const DEFAULT_MAX_BATCH_SIZE: usize = 16 * 1024;
+
+/// Settings for coalescing sent packets into fewer writes.
+///
+/// Packets are still framed individually, so the receiving end does
+/// not need to know about batching.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Batching
+{
+    /// The number of buffered bytes at which the batch is written.
+    pub max_size: usize,
+    /// How long the first packet of a batch may be held back.
+    ///
+    /// This is checked whenever a packet is sent and by
+    /// `Connection::flush_if_due`, so it is not a hard deadline.
+    pub max_delay: Option<Duration>,
+}
+
+/// Packets that have been sent but not yet written to the stream.
+#[derive(Clone, Debug)]
+struct Batch
+{
+    batching: Batching,
+    buffer: Vec<u8>,
+    /// When the first packet of the batch was sent.
+    started_at: Option<Instant>,
+}
 
 /// A stream-based connection.
 // TODO: Allow custom transports.
@@ -14,6 +45,14 @@ pub struct Connection<P: Parcel, S: Read + Write, M: middleware::Pipeline = midd
     pub transport: transport::Simple,
     pub middleware: M,
     pub settings: Settings,
+    /// Limits the rate at which packets are sent, if set.
+    pub rate_limiter: Option<RateLimiter>,
+
+    send_sequence: u64,
+    receive_sequence: u64,
+    batch: Option<Batch>,
+    queue: SendQueue,
+    reassembler: Reassembler,
 
     pub _a: ::std::marker::PhantomData<P>,
 }
@@ -30,6 +69,12 @@ impl<P,S,M> Connection<P,S,M>
             transport: transport::Simple::new(),
             middleware: middleware,
             settings,
+            rate_limiter: None,
+            send_sequence: 0,
+            receive_sequence: 0,
+            batch: None,
+            queue: SendQueue::new(),
+            reassembler: Reassembler::default(),
             _a: ::std::marker::PhantomData,
         }
     }
@@ -41,25 +86,340 @@ impl<P,S,M> Connection<P,S,M>
 
     /// Attempts to receive a packet.
     pub fn receive_packet(&mut self) -> Result<Option<P>, Error> {
+        Ok(self.receive_packet_with_context()?.map(|(packet, _)| packet))
+    }
+
+    /// Attempts to receive a packet, along with the context that the
+    /// middleware decoded it with.
+    pub fn receive_packet_with_context(&mut self) -> Result<Option<(P, Context)>, Error> {
         self.process_incoming_data()?;
 
-        if let Some(raw_packet) = self.transport.receive_raw_packet()? {
-            let mut packet_data = Cursor::new(self.middleware.decode_data(raw_packet)?);
+        while let Some((raw_frame, kind)) = self.transport.receive_raw_frame() {
+            let mut context = Context::incoming(self.receive_sequence);
+            self.receive_sequence += 1;
 
-            let packet = P::read(&mut packet_data, &self.settings)?;
+            let mut frame = Frame::from_vec(raw_frame);
+            self.middleware.decode_with_context(&mut frame, &mut context)?;
 
-            Ok(Some(packet))
-        } else {
-            Ok(None)
+            let packet = match kind {
+                FrameKind::Packet => P::read(&mut Cursor::new(&frame[..]), &self.settings)?,
+                FrameKind::Chunk => match self.reassembler.receive(&frame)? {
+                    Some(packet_data) => P::read(&mut Cursor::new(&packet_data[..]), &self.settings)?,
+                    None => continue,
+                },
+            };
+
+            return Ok(Some((packet, context)));
         }
+
+        Ok(None)
     }
 
     /// Sends a packet.
+    ///
+    /// The stream is flushed afterwards, so that buffered streams
+    /// such as stdout do not hold on to the packet. When batching is
+    /// enabled, the packet is buffered until the batch is written.
     pub fn send_packet(&mut self, packet: &P) -> Result<(), Error> {
-        let raw_packet = self.middleware.encode_data(packet.raw_bytes(&self.settings)?)?;
-        self.transport.send_raw_packet(&mut self.stream, &raw_packet, &self.settings)
+        self.send_packet_with(packet, Metadata::new())
     }
 
+    /// Sends a packet, attaching metadata for the middleware to see.
+    ///
+    /// With a rate limiter that rejects packets, this fails with
+    /// `ErrorKind::RateLimitExceeded` before anything is encoded.
+    pub fn send_packet_with(&mut self,
+                            packet: &P,
+                            metadata: Metadata) -> Result<(), Error> {
+        if let Some(ref mut rate_limiter) = self.rate_limiter {
+            rate_limiter.acquire()?;
+        }
+
+        let mut context = Context::outgoing(self.send_sequence, P::TYPE_NAME, metadata);
+        self.send_sequence += 1;
+
+        let mut frame = Frame::with_headroom(frame::DEFAULT_HEADROOM);
+        packet.write(&mut frame, &self.settings)?;
+        self.middleware.encode_with_context(&mut frame, &mut context)?;
+        self.charge_rate_limiter(frame.len());
+
+        // A queued frame that was partially written has to be finished
+        // before anything else can be written. Only that frame and this
+        // one are written, the rest of the queue is left for `send_queued`.
+        if self.queue.has_unwritten_output() {
+            self.transport.send_raw_packet(&mut self.queue.output, &frame, &self.settings)?;
+            if self.write_queue_output()? {
+                self.stream.flush()?;
+            }
+            return Ok(());
+        }
+
+        match self.batch {
+            Some(ref mut batch) => {
+                self.transport.send_raw_packet(&mut batch.buffer, &frame, &self.settings)?;
+
+                let now = Instant::now();
+                batch.started_at.get_or_insert(now);
+                self.flush_if_due(now)
+            },
+            None => {
+                self.transport.send_raw_packet(&mut self.stream, &frame, &self.settings)?;
+                self.stream.flush()?;
+                Ok(())
+            },
+        }
+    }
+
+    /// Enables or disables batching of sent packets.
+    ///
+    /// Packets that are already batched are written when batching is
+    /// disabled, but not when the settings are changed.
+    pub fn set_batching(&mut self, batching: Option<Batching>) -> Result<(), Error> {
+        match batching {
+            Some(batching) => match self.batch {
+                Some(ref mut batch) => batch.batching = batching,
+                None => self.batch = Some(Batch { batching, buffer: Vec::new(), started_at: None }),
+            },
+            None => {
+                self.flush()?;
+                self.batch = None;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Gets the batching settings, if batching is enabled.
+    pub fn batching(&self) -> Option<&Batching> {
+        self.batch.as_ref().map(|batch| &batch.batching)
+    }
+
+    /// Gets the number of bytes that are batched but not yet written.
+    pub fn batched_size(&self) -> usize {
+        self.batch.as_ref().map_or(0, |batch| batch.buffer.len())
+    }
+
+    /// Writes all batched packets to the stream and flushes it.
+    ///
+    /// If a queued packet has been partially written, its frame is
+    /// finished first. When a non-blocking stream would block, the rest
+    /// is written by the next call to `flush` or `send_queued`.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.take_batch();
+
+        if self.write_queue_output()? {
+            self.stream.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues a packet to be sent by `send_queued`.
+    ///
+    /// Packets larger than the chunk size are split into chunks, so
+    /// that packets with a higher priority can be sent in between them.
+    /// Queued packets are only encoded by the middleware once they are
+    /// about to be written, or when the middleware is changed by
+    /// `switch`.
+    pub fn queue_packet(&mut self,
+                        packet: &P,
+                        priority: Priority) -> Result<(), Error> {
+        let data = packet.raw_bytes(&self.settings)?;
+        self.queue.push(data, P::TYPE_NAME, priority);
+        Ok(())
+    }
+
+    /// Writes queued packets until the queue is empty or the stream
+    /// would block.
+    ///
+    /// Returns `true` once everything has been written. Connections
+    /// over non-blocking streams should call this again whenever the
+    /// stream becomes writable.
+    ///
+    /// Batched packets are written ahead of queued packets, so that
+    /// frames are written in the order they were encoded. A rate
+    /// limiter that rejects packets also makes this return `false`
+    /// while it is out of tokens.
+    pub fn send_queued(&mut self) -> Result<bool, Error> {
+        loop {
+            if self.queue.has_unwritten_output() {
+                if !self.write_queue_output()? {
+                    return Ok(false);
+                }
+            } else if self.batched_size() > 0 {
+                self.take_batch();
+            } else if self.queue.has_packets() {
+                if let Some(ref mut rate_limiter) = self.rate_limiter {
+                    match rate_limiter.acquire() {
+                        Err(crate::Error(ErrorKind::RateLimitExceeded(..), _)) => return Ok(false),
+                        result => result?,
+                    }
+                }
+
+                self.encode_next_queued()?;
+            } else {
+                self.stream.flush()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Checks if there are queued packets that have not been
+    /// completely written, including batched packets whose flush
+    /// would have blocked.
+    pub fn has_queued(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Sets the largest amount of packet data sent in one chunk.
+    ///
+    /// Defaults to 16 KiB. Smaller chunks let urgent packets through
+    /// sooner, at the cost of a few bytes of framing per chunk.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chunk size is zero.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        assert!(chunk_size > 0, "chunk size must not be zero");
+        self.queue.chunk_size = chunk_size;
+    }
+
+    /// Sets the largest packet that received chunks are reassembled into.
+    ///
+    /// Defaults to 16 MiB. Larger chunked packets fail to be received
+    /// with `ErrorKind::PacketTooLarge`, and the rest of their chunks
+    /// are dropped.
+    pub fn set_max_reassembled_size(&mut self, max_size: usize) {
+        self.reassembler.max_packet_size = max_size;
+    }
+
+    /// Writes the batched packets if the batch has grown too large or
+    /// its first packet has waited for too long.
+    ///
+    /// Applications with a delay configured should call this
+    /// periodically, as a batch is otherwise only checked when the next
+    /// packet is sent.
+    pub fn flush_if_due(&mut self, now: Instant) -> Result<(), Error> {
+        let due = match self.batch {
+            Some(ref batch) => batch.is_due(now),
+            None => false,
+        };
+
+        if due { self.flush() } else { Ok(()) }
+    }
+
+    /// Changes the middleware or settings, starting at the next frame.
+    ///
+    /// This is for protocols that enable compression or encryption at
+    /// an exact packet boundary. The change applies to every packet
+    /// sent after this call, and to every packet received after the
+    /// last one returned by `receive_packet`, including any that have
+    /// already been read from the stream and are waiting in the
+    /// transport.
+    ///
+    /// Both ends must switch at the same packet. Typically the sender
+    /// switches right after sending a packet that announces the change,
+    /// and the receiver switches right after receiving it.
+    ///
+    /// Packets that are still queued were queued before the change, so
+    /// they are encoded with the old middleware and settings first. They
+    /// are then written after the batched packets, and ahead of any
+    /// packet sent after the switch, regardless of its priority.
+    pub fn switch<F>(&mut self, change: F) -> Result<(), Error>
+        where F: FnOnce(&mut M, &mut Settings) {
+        // Batched frames were encoded before the queued ones.
+        self.take_batch();
+
+        while self.queue.has_packets() {
+            self.encode_next_queued()?;
+        }
+
+        let old_settings = self.settings.clone();
+        change(&mut self.middleware, &mut self.settings);
+
+        // Frames waiting in the transport were split up using the old
+        // settings.
+        if self.settings != old_settings {
+            self.transport.reframe(&old_settings, &self.settings)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the batched packets to the queue output, behind any frame
+    /// that has been partially written.
+    fn take_batch(&mut self) {
+        if let Some(ref mut batch) = self.batch {
+            self.queue.output.append(&mut batch.buffer);
+            batch.started_at = None;
+        }
+    }
+
+    /// Takes the next frame from the queue, encodes it and appends it
+    /// to the queue output.
+    fn encode_next_queued(&mut self) -> Result<(), Error> {
+        let outgoing = self.queue.pop().expect("queue has packets");
+        let mut context = Context::outgoing(self.send_sequence, outgoing.type_name, Metadata::new());
+        self.send_sequence += 1;
+
+        let mut frame = Frame::from_vec(outgoing.data);
+        self.middleware.encode_with_context(&mut frame, &mut context)?;
+        self.charge_rate_limiter(frame.len());
+
+        if outgoing.is_chunk {
+            self.transport.send_raw_chunk(&mut self.queue.output, &frame, &self.settings)?;
+        } else {
+            self.transport.send_raw_packet(&mut self.queue.output, &frame, &self.settings)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the frames that were already taken from the queue,
+    /// returning `false` if the stream would block first.
+    fn write_queue_output(&mut self) -> Result<bool, Error> {
+        while self.queue.has_unwritten_output() {
+            match self.stream.write(&self.queue.output[self.queue.written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(bytes_written) => self.queue.advance(bytes_written),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn charge_rate_limiter(&mut self, frame_size: usize) {
+        if let Some(ref mut rate_limiter) = self.rate_limiter {
+            rate_limiter.consume(std::mem::size_of::<PacketSize>() + frame_size);
+        }
+    }
+
+    /// Gets the underlying stream.
+    ///
+    /// Batched and queued packets that have not been written are
+    /// discarded.
     pub fn into_inner(self) -> S { self.stream }
 }
 
+impl Batch
+{
+    fn is_due(&self, now: Instant) -> bool {
+        let waited_too_long = match (self.started_at, self.batching.max_delay) {
+            (Some(started_at), Some(max_delay)) => now.saturating_duration_since(started_at) >= max_delay,
+            _ => false,
+        };
+
+        self.buffer.len() >= self.batching.max_size || waited_too_long
+    }
+}
+
+impl Default for Batching
+{
+    fn default() -> Self {
+        Batching { max_size: DEFAULT_MAX_BATCH_SIZE, max_delay: None }
+    }
+}
+
@@ -0,0 +1,56 @@
+use std::io::{self, prelude::*};
+
+/// A stream made up of separate read and write halves.
+///
+/// Useful for connections where the two directions are distinct
+/// handles, such as the stdin and stdout pipes of a child process.
+///
+/// # Example
+///
+/// ```
+/// use protocol::wire::{middleware, stream};
+/// use std::io::Cursor;
+///
+/// let duplex = stream::Duplex::new(Cursor::new(Vec::new()), Vec::new());
+/// let mut connection = stream::Connection::<u8, _>::new(duplex, middleware::pipeline::default(), protocol::Settings::default());
+///
+/// connection.send_packet(&7).unwrap();
+/// assert_eq!(connection.stream.writer, vec![0, 0, 0, 1, 7]);
+/// ```
+#[derive(Debug)]
+pub struct Duplex<R: Read, W: Write>
+{
+    pub reader: R,
+    pub writer: W,
+}
+
+impl<R,W> Duplex<R,W>
+    where R: Read, W: Write
+{
+    /// Creates a new duplex stream.
+    pub fn new(reader: R, writer: W) -> Self {
+        Duplex { reader, writer }
+    }
+
+    pub fn into_inner(self) -> (R, W) { (self.reader, self.writer) }
+}
+
+impl<R,W> Read for Duplex<R,W>
+    where R: Read, W: Write
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R,W> Write for Duplex<R,W>
+    where R: Read, W: Write
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
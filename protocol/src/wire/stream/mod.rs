@@ -1,6 +1,13 @@
 pub use self::transport::Transport;
-pub use self::connection::Connection;
+pub use self::connection::{Batching, Connection};
+pub use self::duplex::Duplex;
+pub use self::queue::Priority;
 
 mod transport;
 mod connection;
+mod duplex;
+mod queue;
+pub mod process;
+#[cfg(target_os = "linux")] pub mod shm;
+#[cfg(feature = "tls")] pub mod tls;
 
@@ -0,0 +1,48 @@
+//! Connections to other processes over their standard input and output.
+//!
+//! The parent spawns a worker with [spawn] and talks to it over the
+//! child's stdin and stdout. The worker calls [stdio] to get the other
+//! end of the same connection. Stderr is left alone so that the worker
+//! can still log to it.
+//!
+//! Nothing else may be written to the worker's stdout, as it would be
+//! interpreted as packet data by the parent.
+
+use crate::{wire::middleware, wire::stream::{Connection, Duplex}, Error, Parcel, Settings};
+
+use std::io::{Stdin, Stdout};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// A parent's connection to a child process.
+pub type ChildConnection<P, M> = Connection<P, Duplex<ChildStdout, ChildStdin>, M>;
+
+/// A child process's connection to its parent.
+pub type ParentConnection<P, M> = Connection<P, Duplex<Stdin, Stdout>, M>;
+
+/// Spawns a child process and connects to its stdin and stdout.
+///
+/// The command's stdin and stdout are replaced with pipes. The child
+/// handle is returned so that the caller can wait for it to exit.
+pub fn spawn<P, M>(command: &mut Command,
+                   middleware: M,
+                   settings: Settings)
+    -> Result<(Child, ChildConnection<P, M>), Error>
+    where P: Parcel, M: middleware::Pipeline {
+    let mut child = command.stdin(Stdio::piped())
+                           .stdout(Stdio::piped())
+                           .spawn()?;
+
+    let stdin = child.stdin.take().expect("stdin was piped");
+    let stdout = child.stdout.take().expect("stdout was piped");
+
+    Ok((child, Connection::new(Duplex::new(stdout, stdin), middleware, settings)))
+}
+
+/// Connects to the parent process over the current process's stdin
+/// and stdout.
+pub fn stdio<P, M>(middleware: M,
+                   settings: Settings)
+    -> ParentConnection<P, M>
+    where P: Parcel, M: middleware::Pipeline {
+    Connection::new(Duplex::new(std::io::stdin(), std::io::stdout()), middleware, settings)
+}
@@ -0,0 +1,278 @@
+//! Outgoing packet queues with priorities.
+
+use crate::{Error, ErrorKind};
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// The priority of a queued packet.
+///
+/// Packets with a higher priority are sent first. Packets with the
+/// same priority are sent in the order they were queued.
+pub type Priority = u8;
+
+/// The default largest frame that a queued packet is sent in.
+pub const DEFAULT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// The default largest packet that chunks are reassembled into.
+pub const DEFAULT_MAX_REASSEMBLED_SIZE: usize = 16 * 1024 * 1024;
+
+/// The size of the header at the start of every chunk.
+const CHUNK_HEADER_SIZE: usize = 2;
+
+/// Packets waiting to be sent, and the bytes of frames that have not
+/// been completely written yet.
+#[derive(Clone, Debug)]
+pub struct SendQueue
+{
+    levels: BTreeMap<Priority, VecDeque<Queued>>,
+    pub chunk_size: usize,
+    /// Framed bytes waiting to be written to the stream.
+    pub output: Vec<u8>,
+    /// The number of bytes at the start of `output` that were written.
+    pub written: usize,
+}
+
+/// A serialized packet, and how much of it has been sent.
+#[derive(Clone, Debug)]
+struct Queued
+{
+    data: Vec<u8>,
+    sent: usize,
+    type_name: &'static str,
+}
+
+/// The next frame to be sent from a queue.
+#[derive(Debug)]
+pub struct Outgoing
+{
+    pub data: Vec<u8>,
+    pub type_name: &'static str,
+    pub is_chunk: bool,
+}
+
+/// Chunks of packets that have not been completely received.
+///
+/// A packet that is chunked is sent in its entirety before any other
+/// packet of the same priority, so the priority identifies the packet
+/// that a chunk belongs to.
+#[derive(Clone, Debug)]
+pub struct Reassembler
+{
+    partial: HashMap<Priority, Vec<u8>>,
+    /// Priorities whose packet grew too large, and whose remaining
+    /// chunks are dropped.
+    discarding: HashSet<Priority>,
+    /// The largest packet that chunks are reassembled into.
+    pub max_packet_size: usize,
+}
+
+impl SendQueue
+{
+    pub fn new() -> Self {
+        SendQueue {
+            levels: BTreeMap::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            output: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Adds a serialized packet to the queue.
+    pub fn push(&mut self,
+                data: Vec<u8>,
+                type_name: &'static str,
+                priority: Priority) {
+        self.levels.entry(priority).or_default().push_back(Queued { data, sent: 0, type_name });
+    }
+
+    /// Takes the next frame to send from the highest priority packet.
+    ///
+    /// Packets that fit into a single chunk are sent as they are, and
+    /// larger ones are split into chunks.
+    pub fn pop(&mut self) -> Option<Outgoing> {
+        let chunk_size = self.chunk_size;
+        let (&priority, level) = self.levels.iter_mut().next_back()?;
+        let queued = level.front_mut().expect("empty levels are removed");
+
+        let outgoing = if queued.sent == 0 && queued.data.len() <= chunk_size {
+            queued.sent = queued.data.len();
+            Outgoing { data: std::mem::take(&mut queued.data), type_name: queued.type_name, is_chunk: false }
+        } else {
+            let end = queued.data.len().min(queued.sent + chunk_size);
+            let is_last = end == queued.data.len();
+
+            let mut data = Vec::with_capacity(CHUNK_HEADER_SIZE + end - queued.sent);
+            data.extend_from_slice(&[priority, is_last as u8]);
+            data.extend_from_slice(&queued.data[queued.sent..end]);
+            queued.sent = end;
+
+            Outgoing { data, type_name: queued.type_name, is_chunk: true }
+        };
+
+        if queued.sent == queued.data.len() || !outgoing.is_chunk {
+            level.pop_front();
+            if level.is_empty() { self.levels.remove(&priority); }
+        }
+
+        Some(outgoing)
+    }
+
+    /// Checks if there are packets that have not been completely taken.
+    pub fn has_packets(&self) -> bool {
+        !self.levels.is_empty()
+    }
+
+    /// Checks if there are no packets or unwritten bytes left.
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty() && !self.has_unwritten_output()
+    }
+
+    /// Checks if a frame has been partially written to the stream.
+    pub fn has_unwritten_output(&self) -> bool {
+        self.written < self.output.len()
+    }
+
+    /// Marks bytes of the output as written.
+    pub fn advance(&mut self, count: usize) {
+        self.written += count;
+
+        if !self.has_unwritten_output() {
+            self.output.clear();
+            self.written = 0;
+        }
+    }
+}
+
+impl Reassembler
+{
+    /// Adds a decoded chunk, returning the packet it completes.
+    ///
+    /// A packet that grows larger than `max_packet_size` fails with
+    /// `ErrorKind::PacketTooLarge`, and the rest of its chunks are
+    /// dropped.
+    pub fn receive(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if chunk.len() < CHUNK_HEADER_SIZE {
+            return Err(ErrorKind::MalformedFrame("chunk is missing its header").into());
+        }
+
+        let (priority, is_last) = (chunk[0], chunk[1] != 0);
+        let data = &chunk[CHUNK_HEADER_SIZE..];
+
+        if self.discarding.contains(&priority) {
+            if is_last { self.discarding.remove(&priority); }
+            return Ok(None);
+        }
+
+        let partial = self.partial.entry(priority).or_default();
+        let size = partial.len() + data.len();
+
+        if size > self.max_packet_size {
+            self.partial.remove(&priority);
+            if !is_last { self.discarding.insert(priority); }
+            return Err(ErrorKind::PacketTooLarge(size, self.max_packet_size).into());
+        }
+
+        partial.extend_from_slice(data);
+
+        if is_last {
+            Ok(self.partial.remove(&priority))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Default for Reassembler
+{
+    fn default() -> Self {
+        Reassembler {
+            partial: HashMap::new(),
+            discarding: HashSet::new(),
+            max_packet_size: DEFAULT_MAX_REASSEMBLED_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn drain(queue: &mut SendQueue) -> Vec<(Vec<u8>, bool)> {
+        std::iter::from_fn(|| queue.pop()).map(|outgoing| (outgoing.data, outgoing.is_chunk)).collect()
+    }
+
+    #[test]
+    fn higher_priorities_are_sent_first() {
+        let mut queue = SendQueue::new();
+        queue.push(vec![1], "a", 0);
+        queue.push(vec![2], "a", 5);
+        queue.push(vec![3], "a", 0);
+        queue.push(vec![4], "a", 5);
+
+        assert_eq!(drain(&mut queue), vec![(vec![2], false), (vec![4], false), (vec![1], false), (vec![3], false)]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn large_packets_are_chunked_and_reassembled() {
+        let mut queue = SendQueue::new();
+        queue.chunk_size = 2;
+        queue.push(vec![1, 2, 3, 4, 5], "a", 1);
+
+        let chunks = drain(&mut queue);
+        assert_eq!(chunks, vec![
+            (vec![1, 0, 1, 2], true),
+            (vec![1, 0, 3, 4], true),
+            (vec![1, 1, 5], true),
+        ]);
+
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.receive(&chunks[0].0).unwrap(), None);
+        assert_eq!(reassembler.receive(&chunks[1].0).unwrap(), None);
+        assert_eq!(reassembler.receive(&chunks[2].0).unwrap(), Some(vec![1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn chunks_without_a_header_are_malformed() {
+        let mut reassembler = Reassembler::default();
+
+        for chunk in &[&[][..], &[1][..]] {
+            match reassembler.receive(chunk) {
+                Err(Error(ErrorKind::MalformedFrame(..), _)) => (),
+                result => panic!("a {} byte chunk was accepted: {:?}", chunk.len(), result),
+            }
+        }
+    }
+
+    #[test]
+    fn packets_larger_than_the_limit_are_dropped() {
+        let mut reassembler = Reassembler { max_packet_size: 4, ..Reassembler::default() };
+
+        assert_eq!(reassembler.receive(&[1, 0, 1, 2]).unwrap(), None);
+        assert_eq!(reassembler.receive(&[2, 0, 7]).unwrap(), None);
+        match reassembler.receive(&[1, 0, 3, 4, 5]) {
+            Err(Error(ErrorKind::PacketTooLarge(5, 4), _)) => (),
+            result => panic!("an oversized packet was accepted: {:?}", result),
+        }
+
+        // The rest of the oversized packet is dropped, while other
+        // packets are still reassembled.
+        assert_eq!(reassembler.receive(&[1, 1, 6]).unwrap(), None);
+        assert_eq!(reassembler.receive(&[2, 1, 8]).unwrap(), Some(vec![7, 8]));
+        assert_eq!(reassembler.receive(&[1, 1, 1, 2, 3, 4]).unwrap(), Some(vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn urgent_packets_are_sent_between_chunks() {
+        let mut queue = SendQueue::new();
+        queue.chunk_size = 2;
+        queue.push(vec![1, 2, 3, 4], "a", 0);
+
+        assert_eq!(queue.pop().unwrap().data, vec![0, 0, 1, 2]);
+        queue.push(vec![9], "a", 10);
+        assert_eq!(queue.pop().unwrap().data, vec![9]);
+        assert_eq!(queue.pop().unwrap().data, vec![0, 1, 3, 4]);
+        assert!(queue.pop().is_none());
+    }
+}
@@ -0,0 +1,647 @@
+//! A shared memory transport for communication on the same host.
+//!
+//! A [Stream] is a pair of single-producer single-consumer ring buffers
+//! in a memory-mapped file, one for each direction. Placing the file on
+//! a memory-backed filesystem such as `/dev/shm` avoids the kernel
+//! entirely on the data path. Futexes are only used to wake a peer that
+//! is waiting for data or for space.
+//!
+//! `Stream` implements `Read` and `Write`, so it plugs into the usual
+//! `Connection` with the same framing and middleware as any other
+//! stream. Reads never block, use [Stream::wait_readable] or
+//! [Connection::wait_for_packet] to wait for data. Writes block while
+//! the ring is full.
+//!
+//! Requires Linux.
+//!
+//! # Example
+//!
+//! ```
+//! use protocol::wire::{middleware, stream::{shm, Connection}};
+//! use std::time::Duration;
+//!
+//! let path = std::env::temp_dir().join(format!("protocol-shm-doc-{}", std::process::id()));
+//! let settings = protocol::Settings::default();
+//!
+//! let server = shm::Stream::create(&path, 4096).unwrap();
+//! let client = shm::Stream::open(&path).unwrap();
+//!
+//! let mut server = Connection::<String, _>::new(server, middleware::pipeline::default(), settings.clone());
+//! let mut client = Connection::<String, _>::new(client, middleware::pipeline::default(), settings);
+//!
+//! client.send_packet(&"hello".to_owned()).unwrap();
+//! assert_eq!(server.wait_for_packet(Some(Duration::from_secs(1))).unwrap(), Some("hello".to_owned()));
+//!
+//! std::fs::remove_file(path).unwrap();
+//! ```
+
+use crate::{wire::middleware, wire::stream::Connection, Error, Parcel};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::convert::TryFrom;
+use std::{mem, ptr};
+
+/// Identifies a file created by `Stream::create`.
+const MAGIC: u64 = 0x7072_6f74_6f73_686d; // "protoshm"
+
+/// Keeps the fields it wraps on a cache line of their own, so that
+/// the producer and consumer do not contend on the same line.
+#[repr(C, align(64))]
+struct CacheLine<T>(T);
+
+/// The header at the start of the mapped file.
+#[repr(C, align(64))]
+struct FileHeader {
+    magic: u64,
+    /// The size of the data region of each ring.
+    capacity: u64,
+}
+
+/// The shared state of one ring buffer.
+#[repr(C)]
+struct RingHeader {
+    /// The total number of bytes ever written.
+    head: CacheLine<AtomicU64>,
+    /// The total number of bytes ever read.
+    tail: CacheLine<AtomicU64>,
+    signals: CacheLine<Signals>,
+}
+
+/// Futex words and flags used to wake up and shut down the peer.
+#[repr(C)]
+struct Signals {
+    /// Bumped by the producer whenever data is written.
+    data: AtomicU32,
+    /// Bumped by the consumer whenever data is read.
+    space: AtomicU32,
+    consumer_waiting: AtomicU32,
+    producer_waiting: AtomicU32,
+    producer_closed: AtomicU32,
+    consumer_closed: AtomicU32,
+}
+
+/// One direction of a shared memory stream.
+struct Ring {
+    header: *const RingHeader,
+    data: *mut u8,
+    capacity: u64,
+}
+
+/// A bidirectional stream over shared memory.
+pub struct Stream {
+    map: *mut libc::c_void,
+    map_size: usize,
+    /// The ring we produce into.
+    outgoing: Ring,
+    /// The ring we consume from.
+    incoming: Ring,
+    _file: File,
+}
+
+// SAFETY: the mapping is owned by the stream, and each side of each
+// ring is only ever accessed by the single stream that owns that side.
+unsafe impl Send for Stream { }
+
+impl Stream {
+    /// Creates a new shared memory file and connects to it.
+    ///
+    /// Any existing file at the path is replaced. The new file is set up
+    /// under a temporary name and renamed into place, so peers that still
+    /// have the old file mapped keep using it undisturbed. `capacity` is
+    /// the number of bytes each direction can hold before writes block.
+    pub fn create<A>(path: A, capacity: usize) -> io::Result<Self>
+        where A: AsRef<Path> {
+        if capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "shared memory capacity must not be zero"));
+        }
+
+        let map_size = Self::map_size(capacity as u64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "shared memory capacity is too large"))?;
+
+        let temporary_path = Self::temporary_path(path.as_ref());
+        let result = Self::create_at(&temporary_path, capacity as u64, map_size)
+            .and_then(|stream| std::fs::rename(&temporary_path, path).map(|()| stream));
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temporary_path);
+        }
+
+        result
+    }
+
+    /// Connects to a shared memory file made by `Stream::create`.
+    ///
+    /// The file is validated, but the peer that created it is trusted
+    /// not to resize it while it is mapped.
+    pub fn open<A>(path: A) -> io::Result<Self>
+        where A: AsRef<Path> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_size = file.metadata()?.len();
+
+        if file_size < mem::size_of::<FileHeader>() as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a shared memory stream"));
+        }
+
+        let mut header = [0u8; mem::size_of::<FileHeader>()];
+        (&file).read_exact(&mut header)?;
+        // SAFETY: `FileHeader` is plain old data.
+        let header: FileHeader = unsafe { ptr::read_unaligned(header.as_ptr() as *const FileHeader) };
+
+        let map_size = Self::map_size(header.capacity).filter(|&map_size| map_size as u64 == file_size);
+        if header.magic != MAGIC || header.capacity == 0 || map_size.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a shared memory stream"));
+        }
+
+        Stream::map(file, header.capacity, true)
+    }
+
+    /// Waits until there is data to read, or the peer has closed the
+    /// stream.
+    ///
+    /// Returns `false` if the timeout expired first.
+    pub fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let signals = &self.incoming.header().signals.0;
+
+        loop {
+            let sequence = signals.data.load(Ordering::SeqCst);
+            if self.incoming.used()? > 0 || signals.producer_closed.load(Ordering::SeqCst) != 0 {
+                return Ok(true);
+            }
+
+            let remaining = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if remaining > Duration::from_secs(0) => Some(remaining),
+                    _ => return Ok(false),
+                },
+                None => None,
+            };
+
+            signals.consumer_waiting.store(1, Ordering::SeqCst);
+            if self.incoming.used()? == 0 {
+                futex_wait(&signals.data, sequence, remaining);
+            }
+            signals.consumer_waiting.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// Gets the size of the mapping for a given ring capacity, or
+    /// `None` if it does not fit into the address space.
+    fn map_size(capacity: u64) -> Option<usize> {
+        usize::try_from(capacity).ok()
+            .and_then(|capacity| capacity.checked_add(mem::size_of::<RingHeader>()))
+            .and_then(|ring_size| ring_size.checked_mul(2))
+            .and_then(|rings_size| rings_size.checked_add(mem::size_of::<FileHeader>()))
+    }
+
+    /// Gets an unused path next to `path` to set up a new file at.
+    fn temporary_path(path: &Path) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(format!(".{}-{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+        temporary_path.into()
+    }
+
+    /// Creates and initializes a new shared memory file at `path`.
+    fn create_at(path: &Path, capacity: u64, map_size: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+        file.set_len(map_size as u64)?;
+
+        let stream = Stream::map(file, capacity, false)?;
+
+        // SAFETY: the file was just sized to hold the header, and nobody
+        // else can have validated it before the magic number is written.
+        unsafe {
+            let header = stream.map as *mut FileHeader;
+            (*header).capacity = capacity;
+            ptr::write_volatile(&mut (*header).magic, MAGIC);
+        }
+
+        Ok(stream)
+    }
+
+    /// Maps a file whose size has been checked against `capacity`.
+    fn map(file: File, capacity: u64, opener: bool) -> io::Result<Self> {
+        let map_size = Self::map_size(capacity).expect("the file size was checked");
+
+        // SAFETY: mapping a file we have open for reading and writing.
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), map_size, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: the layout is a file header, two ring headers, then
+        // the two data regions, all within the mapping.
+        let ring = |index: usize| unsafe {
+            let headers = (map as *mut u8).add(mem::size_of::<FileHeader>()) as *const RingHeader;
+            let data = (headers.add(2) as *mut u8).add(index * capacity as usize);
+
+            Ring { header: headers.add(index), data, capacity }
+        };
+
+        // The creator produces into the first ring, the opener into the second.
+        let (outgoing, incoming) = if opener { (ring(1), ring(0)) } else { (ring(0), ring(1)) };
+
+        Ok(Stream { map, map_size, outgoing, incoming, _file: file })
+    }
+}
+
+impl Read for Stream {
+    /// Reads whatever data is available without blocking.
+    ///
+    /// Fails with `WouldBlock` if there is no data, and returns zero
+    /// once the peer has closed the stream and all data has been read.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let signals = &self.incoming.header().signals.0;
+        // Check before reading so that data written just before closing is not lost.
+        let closed = signals.producer_closed.load(Ordering::SeqCst) != 0;
+
+        let bytes_read = self.incoming.read(buf)?;
+
+        if bytes_read > 0 {
+            signals.space.fetch_add(1, Ordering::SeqCst);
+            if signals.producer_waiting.load(Ordering::SeqCst) != 0 {
+                futex_wake(&signals.space);
+            }
+            Ok(bytes_read)
+        } else if closed || buf.is_empty() {
+            Ok(0)
+        } else {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "no data in shared memory stream"))
+        }
+    }
+}
+
+impl Write for Stream {
+    /// Writes as much as fits, blocking while the ring is full.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let signals = &self.outgoing.header().signals.0;
+
+        loop {
+            if signals.consumer_closed.load(Ordering::SeqCst) != 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "shared memory stream closed by peer"));
+            }
+
+            let sequence = signals.space.load(Ordering::SeqCst);
+            let bytes_written = self.outgoing.write(buf)?;
+
+            if bytes_written > 0 {
+                signals.data.fetch_add(1, Ordering::SeqCst);
+                if signals.consumer_waiting.load(Ordering::SeqCst) != 0 {
+                    futex_wake(&signals.data);
+                }
+                return Ok(bytes_written);
+            }
+
+            signals.producer_waiting.store(1, Ordering::SeqCst);
+            if self.outgoing.used()? == self.outgoing.capacity {
+                futex_wait(&signals.space, sequence, None);
+            }
+            signals.producer_waiting.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let outgoing = &self.outgoing.header().signals.0;
+        outgoing.producer_closed.store(1, Ordering::SeqCst);
+        outgoing.data.fetch_add(1, Ordering::SeqCst);
+        futex_wake(&outgoing.data);
+
+        let incoming = &self.incoming.header().signals.0;
+        incoming.consumer_closed.store(1, Ordering::SeqCst);
+        incoming.space.fetch_add(1, Ordering::SeqCst);
+        futex_wake(&incoming.space);
+
+        // SAFETY: the rings are not used after this point.
+        unsafe { libc::munmap(self.map, self.map_size); }
+    }
+}
+
+impl std::fmt::Debug for Stream {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Stream")
+           .field("capacity", &self.outgoing.capacity)
+           .field("outgoing", &self.outgoing.used().ok())
+           .field("incoming", &self.incoming.used().ok())
+           .finish()
+    }
+}
+
+impl Ring {
+    fn header(&self) -> &RingHeader {
+        // SAFETY: the header lives as long as the mapping, which
+        // outlives the ring.
+        unsafe { &*self.header }
+    }
+
+    /// Loads the head and tail, along with the number of bytes between
+    /// them.
+    ///
+    /// The peer can write anything to the shared header, so positions
+    /// that are out of order or further apart than the capacity are
+    /// rejected with `InvalidData`.
+    fn positions(&self) -> io::Result<(u64, u64, u64)> {
+        let header = self.header();
+        let head = header.head.0.load(Ordering::SeqCst);
+        let tail = header.tail.0.load(Ordering::SeqCst);
+
+        match head.checked_sub(tail) {
+            Some(used) if used <= self.capacity => Ok((head, tail, used)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "shared memory ring positions are corrupted")),
+        }
+    }
+
+    /// Gets the number of bytes waiting to be read.
+    fn used(&self) -> io::Result<u64> {
+        self.positions().map(|(_, _, used)| used)
+    }
+
+    /// Copies as much of `buf` into the ring as fits.
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let (head, _, used) = self.positions()?;
+
+        let length = std::cmp::min(buf.len() as u64, self.capacity - used);
+        let new_head = head.checked_add(length)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "shared memory ring positions are corrupted"))?;
+
+        self.copy(head, |region, offset, count| unsafe {
+            ptr::copy_nonoverlapping(buf.as_ptr().add(offset), region, count)
+        }, length as usize);
+
+        self.header().head.0.store(new_head, Ordering::SeqCst);
+        Ok(length as usize)
+    }
+
+    /// Copies as much data out of the ring as fits in `buf`.
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (_, tail, used) = self.positions()?;
+
+        let length = std::cmp::min(buf.len() as u64, used);
+        self.copy(tail, |region, offset, count| unsafe {
+            ptr::copy_nonoverlapping(region, buf.as_mut_ptr().add(offset), count)
+        }, length as usize);
+
+        // Cannot overflow, as the tail stays at or behind the head.
+        self.header().tail.0.store(tail + length, Ordering::SeqCst);
+        Ok(length as usize)
+    }
+
+    /// Calls `f` with the one or two contiguous regions that make up
+    /// `length` bytes starting at `position`, wrapping around the end.
+    fn copy<F>(&self, position: u64, mut f: F, length: usize)
+        where F: FnMut(*mut u8, usize, usize) {
+        let start = (position % self.capacity) as usize;
+        let first = std::cmp::min(length, self.capacity as usize - start);
+
+        // SAFETY: both regions lie within the data region of the ring.
+        unsafe {
+            f(self.data.add(start), 0, first);
+            if first < length {
+                f(self.data, first, length - first);
+            }
+        }
+    }
+}
+
+impl<P,M> Connection<P,Stream,M>
+    where P: Parcel, M: middleware::Pipeline
+{
+    /// Waits for a packet to arrive over shared memory.
+    ///
+    /// Returns `Ok(None)` if the timeout expired, or if the peer closed
+    /// the stream, before a whole packet arrived.
+    pub fn wait_for_packet(&mut self, timeout: Option<Duration>) -> Result<Option<P>, Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            if let Some(packet) = self.receive_packet()? {
+                return Ok(Some(packet));
+            }
+
+            let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            let closed = self.stream.incoming.header().signals.0.producer_closed.load(Ordering::SeqCst) != 0;
+            if closed || !self.stream.wait_readable(remaining)? {
+                // One last attempt, in case data arrived along with the timeout.
+                return self.receive_packet();
+            }
+        }
+    }
+}
+
+/// Sleeps until the futex word changes from `expected`, it is woken,
+/// or the timeout expires.
+///
+/// Spurious wakeups are possible, so callers must check their
+/// condition again.
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timeout = timeout.as_ref().map_or(ptr::null(), |timeout| timeout as *const libc::timespec);
+
+    // SAFETY: the futex word is a valid, aligned `u32` in shared memory.
+    unsafe {
+        libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAIT, expected, timeout);
+    }
+}
+
+/// Wakes every process waiting on the futex word.
+fn futex_wake(word: &AtomicU32) {
+    // SAFETY: the futex word is a valid, aligned `u32` in shared memory.
+    unsafe {
+        libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAKE, i32::MAX);
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Stream;
+    use crate::wire::{middleware, stream::Connection};
+    use crate::Settings;
+
+    use std::io::{self, Read, Write};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use std::{fs, thread};
+
+    fn path() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        std::env::temp_dir().join(format!("protocol-shm-test-{}-{}",
+                                          std::process::id(),
+                                          COUNTER.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    #[test]
+    fn bytes_flow_in_both_directions() {
+        let path = path();
+        let mut a = Stream::create(&path, 16).unwrap();
+        let mut b = Stream::open(&path).unwrap();
+
+        a.write_all(b"from a").unwrap();
+        b.write_all(b"from b").unwrap();
+
+        let mut buffer = [0; 16];
+        let bytes_read = b.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..bytes_read], b"from a");
+        let bytes_read = a.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..bytes_read], b"from b");
+
+        assert_eq!(a.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn data_wraps_around_the_ring() {
+        let path = path();
+        let mut a = Stream::create(&path, 10).unwrap();
+        let mut b = Stream::open(&path).unwrap();
+        let mut buffer = [0; 10];
+
+        for i in 0..20u8 {
+            let data = [i; 7];
+            assert_eq!(a.write(&data).unwrap(), 7);
+            assert_eq!(b.read(&mut buffer).unwrap(), 7);
+            assert_eq!(&buffer[..7], &data);
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_return_eof_once_the_peer_is_gone() {
+        let path = path();
+        let mut a = Stream::create(&path, 16).unwrap();
+        let mut b = Stream::open(&path).unwrap();
+
+        a.write_all(b"last words").unwrap();
+        drop(a);
+
+        let mut contents = Vec::new();
+        b.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"last words");
+
+        assert_eq!(b.write(b"hello?").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn opening_other_files_fails() {
+        let path = path();
+        fs::write(&path, vec![0; 4096]).unwrap();
+
+        assert_eq!(Stream::open(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn headers_with_impossible_capacities_are_rejected() {
+        let path = path();
+
+        for &capacity in &[0, u64::MAX, u64::MAX / 2] {
+            let mut header = vec![0; std::mem::size_of::<super::FileHeader>()];
+            header[..8].copy_from_slice(&super::MAGIC.to_ne_bytes());
+            header[8..16].copy_from_slice(&capacity.to_ne_bytes());
+            fs::write(&path, &header).unwrap();
+
+            assert_eq!(Stream::open(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_ring_positions_are_rejected() {
+        use std::sync::atomic::Ordering;
+
+        let path = path();
+        let mut a = Stream::create(&path, 16).unwrap();
+        let mut b = Stream::open(&path).unwrap();
+        let mut buffer = [0; 16];
+
+        // A tail ahead of the head.
+        b.incoming.header().tail.0.store(5, Ordering::SeqCst);
+        assert_eq!(b.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(a.write(b"x").unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // A head further ahead than the capacity.
+        b.incoming.header().head.0.store(100, Ordering::SeqCst);
+        assert_eq!(b.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(b.wait_readable(None).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // A head about to overflow.
+        b.incoming.header().head.0.store(u64::MAX, Ordering::SeqCst);
+        b.incoming.header().tail.0.store(u64::MAX, Ordering::SeqCst);
+        assert_eq!(a.write(b"x").unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn recreating_a_file_leaves_existing_peers_alone() {
+        let path = path();
+        let mut a = Stream::create(&path, 4096).unwrap();
+        let mut b = Stream::open(&path).unwrap();
+
+        let mut c = Stream::create(&path, 16).unwrap();
+        let mut d = Stream::open(&path).unwrap();
+
+        let mut buffer = [0; 16];
+        a.write_all(b"old").unwrap();
+        assert_eq!(b.read(&mut buffer).unwrap(), 3);
+        assert_eq!(&buffer[..3], b"old");
+
+        c.write_all(b"new").unwrap();
+        assert_eq!(d.read(&mut buffer).unwrap(), 3);
+        assert_eq!(&buffer[..3], b"new");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn packets_larger_than_the_ring_are_streamed_between_threads() {
+        let path = path();
+        let server = Stream::create(&path, 64).unwrap();
+        let client = Stream::open(&path).unwrap();
+
+        let sender = thread::spawn(move || {
+            let mut client = Connection::<Vec<u8>, _>::new(client, middleware::pipeline::default(), Settings::default());
+
+            for i in 0..200usize {
+                client.send_packet(&vec![i as u8; i * 3]).unwrap();
+            }
+        });
+
+        let mut server = Connection::<Vec<u8>, _>::new(server, middleware::pipeline::default(), Settings::default());
+        for i in 0..200usize {
+            let packet = server.wait_for_packet(Some(Duration::from_secs(10))).unwrap();
+            assert_eq!(packet, Some(vec![i as u8; i * 3]));
+        }
+
+        sender.join().unwrap();
+        assert_eq!(server.wait_for_packet(Some(Duration::from_millis(10))).unwrap(), None);
+        fs::remove_file(path).unwrap();
+    }
+}
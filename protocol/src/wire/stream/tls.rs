@@ -0,0 +1,300 @@
+//! TLS secured connections over TCP.
+//!
+//! Requires the `tls` crate feature to be enabled.
+//!
+//! The TLS handshake is completed by [connect] and [accept] before the
+//! connection is returned, so the socket must be in blocking mode until
+//! then. A non-blocking socket makes them fail with `WouldBlock`, and the
+//! half-finished handshake cannot be resumed. The socket can be switched
+//! to non-blocking mode afterwards through `connection.stream.sock`, and
+//! the connection then works with `send_queued` like any other.
+//!
+//! Cryptography is provided by `ring`.
+//!
+//! Packets are framed and passed through the middleware pipeline the
+//! same way as on any other stream; TLS only sees the framed bytes.
+
+pub use rustls;
+
+use crate::{wire::middleware, wire::stream::Connection, Error, ErrorKind, Parcel, Settings};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore, ServerConfig, StreamOwned};
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+/// A client's connection to a TLS server.
+pub type ClientConnection<P, M> = Connection<P, StreamOwned<rustls::ClientConnection, TcpStream>, M>;
+
+/// A server's connection to a TLS client.
+pub type ServerConnection<P, M> = Connection<P, StreamOwned<rustls::ServerConnection, TcpStream>, M>;
+
+/// A certificate chain and the private key of its first certificate.
+///
+/// All certificates and keys are DER encoded.
+#[derive(Debug)]
+pub struct Identity
+{
+    pub certificates: Vec<CertificateDer<'static>>,
+    pub private_key: PrivateKeyDer<'static>,
+}
+
+impl Identity
+{
+    /// Creates a new identity from DER encoded certificates and a
+    /// DER encoded PKCS#8, PKCS#1 or SEC1 private key.
+    ///
+    /// Fails if the key is in none of these formats.
+    pub fn new(certificates: Vec<Vec<u8>>,
+               private_key: Vec<u8>) -> Result<Self, Error> {
+        let private_key = PrivateKeyDer::try_from(private_key)
+            .map_err(|reason| rustls::Error::General(reason.to_owned()))?;
+
+        Ok(Identity {
+            certificates: certificates.into_iter().map(CertificateDer::from).collect(),
+            private_key,
+        })
+    }
+}
+
+impl Clone for Identity
+{
+    fn clone(&self) -> Self {
+        Identity {
+            certificates: self.certificates.clone(),
+            private_key: self.private_key.clone_key(),
+        }
+    }
+}
+
+/// Builds a client configuration.
+///
+/// The server must present a certificate signed by one of the given
+/// DER encoded root certificates. The identity is presented to
+/// servers that require client authentication.
+pub fn client_config(root_certificates: &[Vec<u8>],
+                     identity: Option<Identity>)
+    -> Result<Arc<ClientConfig>, Error> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store(root_certificates)?);
+
+    let config = match identity {
+        Some(identity) => builder.with_client_auth_cert(identity.certificates, identity.private_key)?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Builds a server configuration.
+///
+/// If client root certificates are given, clients must present a
+/// certificate signed by one of them.
+pub fn server_config(identity: Identity,
+                     client_root_certificates: Option<&[Vec<u8>]>)
+    -> Result<Arc<ServerConfig>, Error> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match client_root_certificates {
+        Some(roots) => {
+            let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(roots)?), provider())
+                .build()
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(builder.with_single_cert(identity.certificates, identity.private_key)?))
+}
+
+/// Connects to a TLS server over an established TCP stream.
+///
+/// The server's certificate must be valid for `server_name`. The stream
+/// must be in blocking mode, as the handshake is completed before this
+/// returns.
+pub fn connect<P, M>(stream: TcpStream,
+                     server_name: &str,
+                     config: Arc<ClientConfig>,
+                     middleware: M,
+                     settings: Settings)
+    -> Result<ClientConnection<P, M>, Error>
+    where P: Parcel, M: middleware::Pipeline {
+    let name = ServerName::try_from(server_name.to_owned())
+        .map_err(|_| ErrorKind::InvalidServerName(server_name.to_owned()))?;
+    let session = rustls::ClientConnection::new(config, name)?;
+
+    let stream = handshake(StreamOwned::new(session, stream))?;
+    Ok(Connection::new(stream, middleware, settings))
+}
+
+/// Accepts a TLS client over an established TCP stream.
+///
+/// The stream must be in blocking mode, as the handshake is completed
+/// before this returns.
+pub fn accept<P, M>(stream: TcpStream,
+                    config: Arc<ServerConfig>,
+                    middleware: M,
+                    settings: Settings)
+    -> Result<ServerConnection<P, M>, Error>
+    where P: Parcel, M: middleware::Pipeline {
+    let session = rustls::ServerConnection::new(config)?;
+
+    let stream = handshake(StreamOwned::new(session, stream))?;
+    Ok(Connection::new(stream, middleware, settings))
+}
+
+/// Drives the handshake to completion.
+fn handshake<C, T, S>(mut stream: StreamOwned<C, T>)
+    -> Result<StreamOwned<C, T>, Error>
+    where C: std::ops::DerefMut<Target=rustls::ConnectionCommon<S>>,
+          T: Read + Write,
+          S: rustls::SideData {
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+
+    Ok(stream)
+}
+
+fn root_store(certificates: &[Vec<u8>]) -> Result<RootCertStore, Error> {
+    let mut store = RootCertStore::empty();
+    for certificate in certificates {
+        store.add(CertificateDer::from(certificate.clone()))?;
+    }
+    Ok(store)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use crate::wire::middleware;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Generates a self-signed certificate for `localhost`.
+    fn self_signed() -> (Vec<u8>, Identity) {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let der = certificate.serialize_der().unwrap();
+        let identity = Identity::new(vec![der.clone()], certificate.serialize_private_key_der()).unwrap();
+        (der, identity)
+    }
+
+    /// Accepts a single client on a background thread and echoes one
+    /// packet back to it.
+    fn echo_server(config: Arc<ServerConfig>) -> (u16, thread::JoinHandle<Result<(), Error>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            let mut connection: ServerConnection<String, _> = accept(stream, config, middleware::pipeline::default(), Settings::default())?;
+
+            let packet = loop {
+                if let Some(packet) = connection.receive_packet()? { break packet; }
+            };
+            connection.send_packet(&packet)
+        });
+
+        (port, server)
+    }
+
+    fn receive<S: Read + Write>(connection: &mut Connection<String, S, middleware::pipeline::Default>) -> String {
+        loop {
+            if let Some(packet) = connection.receive_packet().unwrap() { return packet; }
+        }
+    }
+
+    #[test]
+    fn packets_are_echoed_over_tls() {
+        let (root, identity) = self_signed();
+        let (port, server) = echo_server(server_config(identity, None).unwrap());
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let config = client_config(&[root], None).unwrap();
+        let mut client: ClientConnection<String, _> = connect(stream, "localhost", config, middleware::pipeline::default(), Settings::default()).unwrap();
+
+        let message = "hello ".repeat(5000);
+        client.send_packet(&message).unwrap();
+        assert_eq!(receive(&mut client), message);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn clients_can_authenticate_with_certificates() {
+        let (server_root, server_identity) = self_signed();
+        let (client_root, client_identity) = self_signed();
+        let (port, server) = echo_server(server_config(server_identity, Some(&[client_root])).unwrap());
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let config = client_config(&[server_root], Some(client_identity)).unwrap();
+        let mut client: ClientConnection<String, _> = connect(stream, "localhost", config, middleware::pipeline::default(), Settings::default()).unwrap();
+
+        client.send_packet(&"authenticated".to_owned()).unwrap();
+        assert_eq!(receive(&mut client), "authenticated");
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn untrusted_servers_are_rejected() {
+        let (_, identity) = self_signed();
+        let (other_root, _) = self_signed();
+        let (port, server) = echo_server(server_config(identity, None).unwrap());
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let config = client_config(&[other_root], None).unwrap();
+        let result: Result<ClientConnection<String, _>, _> = connect(stream, "localhost", config, middleware::pipeline::default(), Settings::default());
+
+        assert!(result.is_err());
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn handshakes_fail_on_non_blocking_sockets() {
+        use std::io;
+
+        let (root, _) = self_signed();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let config = client_config(&[root], None).unwrap();
+
+        // The server never answers, so a blocking handshake would hang.
+        let result: Result<ClientConnection<String, _>, _> = connect(stream, "localhost", config, middleware::pipeline::default(), Settings::default());
+        match result {
+            Err(Error(ErrorKind::Io(ref e), _)) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+            _ => panic!("expected the handshake to fail with WouldBlock"),
+        }
+    }
+
+    #[test]
+    fn invalid_private_keys_are_rejected() {
+        let (root, _) = self_signed();
+        assert!(Identity::new(vec![root], vec![1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn invalid_server_names_are_rejected() {
+        let (root, _) = self_signed();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let config = client_config(&[root], None).unwrap();
+
+        let result: Result<ClientConnection<String, _>, _> = connect(stream, "not a hostname", config, middleware::pipeline::default(), Settings::default());
+        match result {
+            Err(Error(ErrorKind::InvalidServerName(ref name), _)) => assert_eq!(name, "not a hostname"),
+            _ => panic!("expected an invalid server name error"),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-pub use self::simple::Simple;
+pub use self::simple::{FrameKind, Simple};
 
 pub mod simple;
 
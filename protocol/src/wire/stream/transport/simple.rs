@@ -1,15 +1,32 @@
 use super::Transport;
 
-use crate::{Error, Parcel, Settings};
+use crate::{Error, ErrorKind, Parcel, Settings};
 
 use std::collections::VecDeque;
 use std::io::prelude::*;
+use std::io;
 use std::io::Cursor;
 use std::mem;
 
 /// The type that we use to describe packet sizes.
 pub type PacketSize = u32;
 
+/// Set in the size prefix of frames that hold a chunk of a larger packet.
+///
+/// Chunks are reassembled by `Connection`, after they have been decoded
+/// by its middleware. Frames must therefore be smaller than this.
+pub const CHUNK_FLAG: PacketSize = 1 << 31;
+
+/// What a received frame holds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameKind
+{
+    /// A whole packet.
+    Packet,
+    /// A chunk of a packet that was split up by a send queue.
+    Chunk,
+}
+
 /// The current state.
 #[derive(Clone, Debug)]
 enum State
@@ -18,6 +35,7 @@ enum State
     AwaitingSize(Vec<u8>),
     AwaitingPacket {
         size: PacketSize,
+        kind: FrameKind,
         received_data: Vec<u8>,
     },
 }
@@ -27,7 +45,7 @@ enum State
 pub struct Simple
 {
     state: State,
-    packets: VecDeque<Vec<u8>>,
+    packets: VecDeque<(Vec<u8>, FrameKind)>,
 }
 
 impl Simple
@@ -39,6 +57,45 @@ impl Simple
         }
     }
 
+    /// Splits up data that has been received but not yet handed out as
+    /// packets again, as if it had been received with other settings.
+    pub fn reframe(&mut self,
+                   old_settings: &Settings,
+                   new_settings: &Settings) -> Result<(), Error> {
+        let mut pending = Vec::new();
+
+        for (packet, kind) in self.packets.drain(..) {
+            size_prefix(packet.len(), kind)?.write(&mut pending, old_settings)?;
+            pending.extend(packet);
+        }
+
+        match mem::replace(&mut self.state, State::AwaitingSize(Vec::new())) {
+            State::AwaitingSize(size_bytes) => pending.extend(size_bytes),
+            State::AwaitingPacket { size, kind, received_data } => {
+                size_prefix(size as usize, kind)?.write(&mut pending, old_settings)?;
+                pending.extend(received_data);
+            },
+        }
+
+        self.process_bytes(&pending, new_settings)
+    }
+
+    /// Receives a frame, along with what it holds.
+    pub fn receive_raw_frame(&mut self) -> Option<(Vec<u8>, FrameKind)> {
+        self.packets.pop_front()
+    }
+
+    /// Sends a frame holding a chunk of a larger packet.
+    pub fn send_raw_chunk(&mut self,
+                          write: &mut dyn Write,
+                          chunk: &[u8],
+                          settings: &Settings) -> Result<(), Error> {
+        size_prefix(chunk.len(), FrameKind::Chunk)?.write(write, settings)?;
+        write.write_all(chunk)?;
+
+        Ok(())
+    }
+
     fn process_bytes(&mut self,
                      bytes: &[u8],
                      settings: &Settings)
@@ -62,16 +119,17 @@ impl Simple
                         let mut size_buffer = Cursor::new(size_bytes);
 
                         let size = PacketSize::read(&mut size_buffer, settings).unwrap();
+                        let kind = if size & CHUNK_FLAG != 0 { FrameKind::Chunk } else { FrameKind::Packet };
 
                         // We are now ready to receive packet data.
-                        self.state = State::AwaitingPacket { size: size, received_data: Vec::new() }
+                        self.state = State::AwaitingPacket { size: size & !CHUNK_FLAG, kind, received_data: Vec::new() }
                     } else {
                         // Still waiting to receive the whole packet.
                         self.state = State::AwaitingSize(size_bytes);
                         break;
                     }
                 },
-                State::AwaitingPacket { size, mut received_data } => {
+                State::AwaitingPacket { size, kind, mut received_data } => {
                     let remaining_bytes = (size as usize) - received_data.len();
                     assert!(remaining_bytes > 0);
 
@@ -86,13 +144,13 @@ impl Simple
                     assert!(received_data.len() <= (size as usize));
 
                     if (size as usize) == received_data.len() {
-                        self.packets.push_back(received_data);
+                        self.packets.push_back((received_data, kind));
 
                         // Start reading the next packet.
                         self.state = State::AwaitingSize(Vec::new());
                     } else {
                         // Keep reading the current packet.
-                        self.state = State::AwaitingPacket { size: size, received_data: received_data };
+                        self.state = State::AwaitingPacket { size: size, kind, received_data: received_data };
                         break;
                     }
                 },
@@ -105,6 +163,21 @@ impl Simple
 
 const BUFFER_SIZE: usize = 10000;
 
+/// Builds the size prefix of a frame.
+///
+/// Fails if the frame is too large for its size to be told apart from
+/// the chunk flag.
+fn size_prefix(size: usize, kind: FrameKind) -> Result<PacketSize, Error> {
+    if size >= CHUNK_FLAG as usize {
+        return Err(ErrorKind::PacketTooLarge(size, CHUNK_FLAG as usize - 1).into());
+    }
+
+    Ok(match kind {
+        FrameKind::Packet => size as PacketSize,
+        FrameKind::Chunk => size as PacketSize | CHUNK_FLAG,
+    })
+}
+
 impl Transport for Simple
 {
     fn process_data(&mut self,
@@ -113,7 +186,13 @@ impl Transport for Simple
         // Load the data into a temporary buffer before we process it.
         loop {
             let mut buffer = [0u8; BUFFER_SIZE];
-            let bytes_read = read.read(&mut buffer).unwrap();
+            let bytes_read = match read.read(&mut buffer) {
+                Ok(bytes_read) => bytes_read,
+                // A non-blocking stream has no more data for now.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
             let buffer = &buffer[0..bytes_read];
 
             if bytes_read == 0 {
@@ -134,15 +213,24 @@ impl Transport for Simple
                        packet: &[u8],
                        settings: &Settings) -> Result<(), Error> {
         // Prefix the packet size.
-        (packet.len() as PacketSize).write(write, settings)?;
+        size_prefix(packet.len(), FrameKind::Packet)?.write(write, settings)?;
         // Write the packet data.
-        write.write(&packet)?;
+        write.write_all(packet)?;
 
         Ok(())
     }
 
+    /// Receives the next whole packet.
+    ///
+    /// Chunks of larger packets cannot be handed out as packets, so they
+    /// are reported as malformed. Use `receive_raw_frame` to receive them.
     fn receive_raw_packet(&mut self) -> Result<Option<Vec<u8>>, Error> {
-        Ok(self.packets.pop_front())
+        match self.receive_raw_frame() {
+            Some((packet, FrameKind::Packet)) => Ok(Some(packet)),
+            Some((_, FrameKind::Chunk)) => Err(ErrorKind::MalformedFrame(
+                "received a chunk where a whole packet was expected").into()),
+            None => Ok(None),
+        }
     }
 }
 
@@ -188,5 +276,78 @@ mod test
         let read_data = transport.receive_raw_packet().ok().unwrap().unwrap();
         assert_eq!(&read_data, &data);
     }
-}
 
+    #[test]
+    fn reframes_received_data_with_new_settings() {
+        use crate::ByteOrder;
+
+        let big_endian = Settings::default();
+        let little_endian = Settings { byte_order: ByteOrder::LittleEndian };
+
+        let mut buffer = Vec::new();
+        let mut transport = Simple::new();
+        transport.send_raw_packet(&mut buffer, &[1], &big_endian).unwrap();
+        transport.send_raw_packet(&mut buffer, &[2, 2], &little_endian).unwrap();
+        transport.send_raw_packet(&mut buffer, &[3, 3, 3], &little_endian).unwrap();
+
+        // Stop halfway through the last packet.
+        transport.process_data(&mut Cursor::new(&buffer[..buffer.len() - 1]), &big_endian).unwrap();
+        assert_eq!(transport.receive_raw_packet().unwrap(), Some(vec![1]));
+
+        transport.reframe(&big_endian, &little_endian).unwrap();
+        assert_eq!(transport.receive_raw_packet().unwrap(), Some(vec![2, 2]));
+        assert_eq!(transport.receive_raw_packet().unwrap(), None);
+
+        transport.process_data(&mut Cursor::new(&buffer[buffer.len() - 1..]), &little_endian).unwrap();
+        assert_eq!(transport.receive_raw_packet().unwrap(), Some(vec![3, 3, 3]));
+    }
+
+    #[test]
+    fn frames_that_would_set_the_chunk_flag_are_rejected() {
+        use super::{size_prefix, FrameKind, CHUNK_FLAG};
+        use crate::{Error, ErrorKind};
+
+        assert_eq!(size_prefix(CHUNK_FLAG as usize - 1, FrameKind::Packet).unwrap(), CHUNK_FLAG - 1);
+
+        for &size in &[CHUNK_FLAG as usize, u32::MAX as usize, u32::MAX as usize + 2] {
+            for &kind in &[FrameKind::Packet, FrameKind::Chunk] {
+                match size_prefix(size, kind) {
+                    Err(Error(ErrorKind::PacketTooLarge(..), _)) => (),
+                    result => panic!("a {} byte {:?} got a size prefix: {:?}", size, kind, result),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_are_flagged_in_the_size_prefix() {
+        use super::FrameKind;
+
+        let mut buffer = Vec::new();
+        let mut transport = Simple::new();
+        transport.send_raw_chunk(&mut buffer, &[1, 2], &Settings::default()).unwrap();
+        transport.send_raw_packet(&mut buffer, &[3], &Settings::default()).unwrap();
+        assert_eq!(&buffer[..4], &[0x80, 0x00, 0x00, 0x02]);
+
+        transport.process_data(&mut Cursor::new(buffer), &Settings::default()).unwrap();
+        assert_eq!(transport.receive_raw_frame(), Some((vec![1, 2], FrameKind::Chunk)));
+        assert_eq!(transport.receive_raw_frame(), Some((vec![3], FrameKind::Packet)));
+    }
+
+    #[test]
+    fn chunks_are_not_received_as_packets() {
+        use crate::{Error, ErrorKind};
+
+        let mut buffer = Vec::new();
+        let mut transport = Simple::new();
+        transport.send_raw_chunk(&mut buffer, &[1, 2], &Settings::default()).unwrap();
+        transport.send_raw_packet(&mut buffer, &[3], &Settings::default()).unwrap();
+
+        transport.process_data(&mut Cursor::new(buffer), &Settings::default()).unwrap();
+        match transport.receive_raw_packet() {
+            Err(Error(ErrorKind::MalformedFrame(..), _)) => (),
+            result => panic!("a chunk was received as a packet: {:?}", result),
+        }
+        assert_eq!(transport.receive_raw_packet().unwrap(), Some(vec![3]));
+    }
+}
@@ -34,3 +34,491 @@ fn can_write_and_read_back_data() {
                ping.raw_bytes(&settings).unwrap());
 }
 
+
+#[cfg(unix)]
+#[test]
+fn can_talk_over_a_unix_stream() {
+    use std::os::unix::net::UnixStream;
+
+    let settings = Settings::default();
+    let (a, b) = UnixStream::pair().unwrap();
+
+    let mut client = Connection::new(a, middleware::pipeline::default(), settings.clone());
+    let mut server = Connection::new(b, middleware::pipeline::default(), settings);
+
+    let ping = PacketKind::Ping(Ping { data: vec![1, 2, 3] });
+    client.send_packet(&ping).unwrap();
+
+    assert_eq!(server.receive_packet().unwrap(), Some(ping));
+}
+
+#[cfg(unix)]
+#[test]
+fn can_talk_to_a_child_process() {
+    use protocol::wire::stream::process;
+    use std::process::Command;
+
+    // `cat` echoes every frame straight back to us.
+    let (mut child, mut connection) = process::spawn(&mut Command::new("cat"), middleware::pipeline::default(), Settings::default()).unwrap();
+
+    let ping = PacketKind::Ping(Ping { data: vec![9, 8, 7] });
+    connection.send_packet(&ping).unwrap();
+
+    let mut response = None;
+    while response.is_none() {
+        response = connection.receive_packet().unwrap();
+    }
+    assert_eq!(response, Some(ping));
+
+    drop(connection);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn can_switch_middleware_and_settings_at_a_packet_boundary() {
+    use protocol::wire::middleware::rotate_bytes::RotateBytes;
+    use protocol::ByteOrder;
+
+    protocol::define_middleware_pipeline!(Rotating {
+        rotate: RotateBytes
+    });
+
+    let enable = |middleware: &mut Rotating, settings: &mut Settings| {
+        middleware.rotate = RotateBytes::ROT13;
+        settings.byte_order = ByteOrder::LittleEndian;
+    };
+
+    let pings: Vec<_> = (0..3).map(|i| PacketKind::Ping(Ping { data: vec![i; 300] })).collect();
+    let mut connection = Connection::new(Cursor::new(Vec::new()), Rotating { rotate: RotateBytes { amount: 0 } }, Settings::default());
+
+    connection.send_packet(&pings[0]).unwrap();
+    connection.switch(enable).unwrap();
+    connection.send_packet(&pings[1]).unwrap();
+    connection.send_packet(&pings[2]).unwrap();
+
+    let mut connection = Connection::new(Cursor::new(connection.into_inner().into_inner()), Rotating { rotate: RotateBytes { amount: 0 } }, Settings::default());
+
+    // All three packets are read from the stream here.
+    assert_eq!(connection.receive_packet().unwrap().as_ref(), Some(&pings[0]));
+
+    connection.switch(enable).unwrap();
+    assert_eq!(connection.receive_packet().unwrap().as_ref(), Some(&pings[1]));
+    assert_eq!(connection.receive_packet().unwrap().as_ref(), Some(&pings[2]));
+}
+
+#[test]
+fn middleware_sees_the_context_of_each_packet() {
+    use protocol::wire::middleware::{Context, Direction, Frame, Metadata};
+    use protocol::Error;
+
+    /// Marks packets that must not be scrambled.
+    struct Handshake;
+
+    /// Scrambles every packet except handshakes, and records the
+    /// contexts it was given.
+    #[derive(Clone, Debug, Default)]
+    struct Scramble {
+        seen: Vec<(Direction, u64, Option<&'static str>)>,
+    }
+
+    impl middleware::Middleware for Scramble {
+        fn encode_data(&mut self, _: Vec<u8>) -> Result<Vec<u8>, Error> { unreachable!() }
+        fn decode_data(&mut self, _: Vec<u8>) -> Result<Vec<u8>, Error> { unreachable!() }
+
+        fn encode_with_context(&mut self, frame: &mut Frame, context: &mut Context) -> Result<(), Error> {
+            self.seen.push((context.direction, context.sequence, context.type_name));
+
+            if context.metadata.contains::<Handshake>() {
+                frame.prepend(&[0]);
+            } else {
+                frame.iter_mut().for_each(|byte| *byte ^= 0xff);
+                frame.prepend(&[1]);
+            }
+            Ok(())
+        }
+
+        fn decode_with_context(&mut self, frame: &mut Frame, context: &mut Context) -> Result<(), Error> {
+            self.seen.push((context.direction, context.sequence, context.type_name));
+
+            let scrambled = frame[0] == 1;
+            frame.advance(1);
+
+            if scrambled {
+                frame.iter_mut().for_each(|byte| *byte ^= 0xff);
+            } else {
+                context.metadata.insert(Handshake);
+            }
+            Ok(())
+        }
+    }
+
+    protocol::define_middleware_pipeline!(Pipeline {
+        scramble: Scramble
+    });
+
+    let settings = Settings::default();
+    let ping = PacketKind::Ping(Ping { data: vec![1, 2, 3] });
+
+    let mut connection = Connection::new(Cursor::new(Vec::new()), Pipeline { scramble: Scramble::default() }, settings);
+
+    let mut metadata = Metadata::new();
+    metadata.insert(Handshake);
+    connection.send_packet_with(&ping, metadata).unwrap();
+    connection.send_packet(&ping).unwrap();
+
+    // The handshake was sent as is, after its length prefix and flag.
+    let handshake = [0, 0, 0, 0, 0, 0, 0, 0, 3, 1, 2, 3];
+    assert_eq!(&connection.stream.get_ref()[4..16], &handshake);
+
+    connection.stream.set_position(0);
+    let (first, context) = connection.receive_packet_with_context().unwrap().unwrap();
+    assert_eq!(first, ping);
+    assert!(context.metadata.contains::<Handshake>());
+
+    let (second, context) = connection.receive_packet_with_context().unwrap().unwrap();
+    assert_eq!(second, ping);
+    assert!(!context.metadata.contains::<Handshake>());
+
+    assert_eq!(connection.middleware.scramble.seen, vec![
+        (Direction::Outgoing, 0, Some("PacketKind")),
+        (Direction::Outgoing, 1, Some("PacketKind")),
+        (Direction::Incoming, 0, None),
+        (Direction::Incoming, 1, None),
+    ]);
+}
+
+/// A stream that counts how many times it was written to.
+#[derive(Debug, Default)]
+struct CountingStream {
+    data: Vec<u8>,
+    writes: usize,
+}
+
+impl std::io::Read for CountingStream {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> { Ok(0) }
+}
+
+impl std::io::Write for CountingStream {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.writes += 1;
+        self.data.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn batched_packets_are_written_together_and_read_back_separately() {
+    use protocol::wire::stream::Batching;
+
+    let settings = Settings::default();
+    let mut connection: Connection<PacketKind, _> = Connection::new(CountingStream::default(), middleware::pipeline::default(), settings.clone());
+    connection.set_batching(Some(Batching::default())).unwrap();
+
+    for i in 0..10 {
+        connection.send_packet(&PacketKind::Ping(Ping { data: vec![i] })).unwrap();
+    }
+    assert_eq!(connection.stream.writes, 0);
+    assert!(connection.batched_size() > 0);
+
+    connection.flush().unwrap();
+    assert_eq!(connection.stream.writes, 1);
+    assert_eq!(connection.batched_size(), 0);
+
+    let mut receiver: Connection<PacketKind, _> = Connection::new(Cursor::new(connection.stream.data.clone()), middleware::pipeline::default(), settings);
+    for i in 0..10 {
+        assert_eq!(receiver.receive_packet().unwrap(), Some(PacketKind::Ping(Ping { data: vec![i] })));
+    }
+    assert_eq!(receiver.receive_packet().unwrap(), None);
+}
+
+#[test]
+fn batches_are_written_once_they_reach_a_threshold() {
+    use protocol::wire::stream::Batching;
+    use std::time::{Duration, Instant};
+
+    let ping = PacketKind::Ping(Ping { data: vec![1, 2, 3] });
+    let mut connection: Connection<PacketKind, _> = Connection::new(CountingStream::default(), middleware::pipeline::default(), Settings::default());
+
+    // Every ping takes up 15 bytes including its length prefix.
+    connection.set_batching(Some(Batching { max_size: 40, max_delay: None })).unwrap();
+    connection.send_packet(&ping).unwrap();
+    connection.send_packet(&ping).unwrap();
+    assert_eq!(connection.stream.writes, 0);
+    connection.send_packet(&ping).unwrap();
+    assert_eq!((connection.stream.writes, connection.batched_size()), (1, 0));
+
+    connection.set_batching(Some(Batching { max_size: 1000, max_delay: Some(Duration::from_secs(60)) })).unwrap();
+    connection.send_packet(&ping).unwrap();
+    connection.flush_if_due(Instant::now()).unwrap();
+    assert_eq!(connection.stream.writes, 1);
+    connection.flush_if_due(Instant::now() + Duration::from_secs(61)).unwrap();
+    assert_eq!(connection.stream.writes, 2);
+
+    // Disabling batching writes whatever is left.
+    connection.send_packet(&ping).unwrap();
+    connection.set_batching(None).unwrap();
+    assert_eq!(connection.stream.writes, 3);
+    assert_eq!(connection.stream.data.len(), 5 * 15);
+}
+
+/// A non-blocking stream that accepts a few bytes at a time.
+#[derive(Debug, Default)]
+struct TrickleStream {
+    data: Vec<u8>,
+    blocked: bool,
+}
+
+impl std::io::Read for TrickleStream {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> { Ok(0) }
+}
+
+impl std::io::Write for TrickleStream {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        // Block on every other write.
+        self.blocked = !self.blocked;
+        if self.blocked {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+
+        let count = bytes.len().min(7);
+        self.data.extend_from_slice(&bytes[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn batches_are_not_written_twice_when_the_stream_would_block() {
+    use protocol::wire::stream::Batching;
+
+    let settings = Settings::default();
+    let mut sender: Connection<PacketKind, _> = Connection::new(TrickleStream::default(), middleware::pipeline::default(), settings.clone());
+    sender.set_batching(Some(Batching::default())).unwrap();
+
+    for i in 0..5 {
+        sender.send_packet(&PacketKind::Ping(Ping { data: vec![i; 10] })).unwrap();
+    }
+
+    // Every flush writes at most a few bytes before the stream blocks.
+    sender.flush().unwrap();
+    assert_eq!(sender.batched_size(), 0);
+    while sender.has_queued() {
+        sender.flush().unwrap();
+    }
+
+    let mut receiver: Connection<PacketKind, _> = Connection::new(Cursor::new(sender.stream.data.clone()), middleware::pipeline::default(), settings);
+    for i in 0..5 {
+        assert_eq!(receiver.receive_packet().unwrap(), Some(PacketKind::Ping(Ping { data: vec![i; 10] })));
+    }
+    assert_eq!(receiver.receive_packet().unwrap(), None);
+}
+
+#[test]
+fn urgent_packets_overtake_large_queued_packets() {
+    protocol::define_middleware_pipeline!(Rotating {
+        rotate: middleware::rotate_bytes::RotateBytes
+    });
+
+    let settings = Settings::default();
+    let asset = PacketKind::Ping(Ping { data: (0..=255).collect() });
+    let input = PacketKind::Ping(Ping { data: vec![1] });
+
+    let rotating = || Rotating { rotate: middleware::rotate_bytes::RotateBytes::ROT13 };
+    let mut sender: Connection<PacketKind, _, _> = Connection::new(TrickleStream::default(), rotating(), settings.clone());
+    sender.set_chunk_size(64);
+
+    sender.queue_packet(&asset, 0).unwrap();
+    assert!(!sender.send_queued().unwrap());
+    sender.queue_packet(&input, 10).unwrap();
+
+    let mut attempts = 0;
+    while !sender.send_queued().unwrap() {
+        attempts += 1;
+        assert!(attempts < 1000, "queue was never emptied");
+    }
+    assert!(!sender.has_queued());
+
+    let mut receiver: Connection<PacketKind, _, _> = Connection::new(Cursor::new(sender.stream.data.clone()), rotating(), settings);
+    assert_eq!(receiver.receive_packet().unwrap(), Some(input));
+    assert_eq!(receiver.receive_packet().unwrap(), Some(asset));
+    assert_eq!(receiver.receive_packet().unwrap(), None);
+}
+
+#[test]
+fn packets_sent_directly_wait_for_partially_written_chunks() {
+    let settings = Settings::default();
+    let asset = PacketKind::Ping(Ping { data: vec![7; 100] });
+    let input = PacketKind::Ping(Ping { data: vec![1] });
+
+    let mut sender: Connection<PacketKind, _> = Connection::new(TrickleStream::default(), middleware::pipeline::default(), settings.clone());
+    sender.set_chunk_size(32);
+    sender.queue_packet(&asset, 0).unwrap();
+    sender.send_queued().unwrap();
+    sender.send_queued().unwrap();
+    assert!(sender.has_queued());
+
+    // The first chunk is only partially written, so the packet is
+    // written once it has been finished.
+    sender.send_packet(&input).unwrap();
+    while !sender.send_queued().unwrap() {}
+
+    let mut receiver: Connection<PacketKind, _> = Connection::new(Cursor::new(sender.stream.data.clone()), middleware::pipeline::default(), settings);
+    assert_eq!(receiver.receive_packet().unwrap(), Some(input));
+    assert_eq!(receiver.receive_packet().unwrap(), Some(asset));
+}
+
+#[test]
+fn packets_sent_directly_only_finish_the_partially_written_chunk() {
+    let settings = Settings::default();
+    let asset = PacketKind::Ping(Ping { data: vec![7; 100] });
+    let input = PacketKind::Ping(Ping { data: vec![1] });
+
+    let mut sender: Connection<PacketKind, _> = Connection::new(TrickleStream::default(), middleware::pipeline::default(), settings.clone());
+    sender.set_chunk_size(32);
+    sender.queue_packet(&asset, 0).unwrap();
+    sender.send_queued().unwrap();
+    sender.send_queued().unwrap();
+
+    sender.send_packet(&input).unwrap();
+    for _ in 0..100 {
+        sender.flush().unwrap();
+    }
+
+    // The rest of the chunks are still waiting for `send_queued`.
+    assert!(sender.has_queued());
+    let mut receiver: Connection<PacketKind, _> = Connection::new(Cursor::new(sender.stream.data.clone()), middleware::pipeline::default(), settings);
+    assert_eq!(receiver.receive_packet().unwrap(), Some(input));
+    assert_eq!(receiver.receive_packet().unwrap(), None);
+
+    let written = sender.stream.data.len();
+    while !sender.send_queued().unwrap() {}
+    receiver.stream.get_mut().extend_from_slice(&sender.stream.data[written..]);
+    assert_eq!(receiver.receive_packet().unwrap(), Some(asset));
+}
+
+#[test]
+fn queued_packets_are_sent_with_the_middleware_they_were_queued_under() {
+    use protocol::wire::middleware::rotate_bytes::RotateBytes;
+    use protocol::ByteOrder;
+
+    protocol::define_middleware_pipeline!(Rotating {
+        rotate: RotateBytes
+    });
+
+    let enable = |middleware: &mut Rotating, settings: &mut Settings| {
+        middleware.rotate = RotateBytes::ROT13;
+        settings.byte_order = ByteOrder::LittleEndian;
+    };
+
+    let asset = PacketKind::Ping(Ping { data: (0..=255).collect() });
+    let queued = PacketKind::Ping(Ping { data: vec![1] });
+    let after = PacketKind::Ping(Ping { data: vec![2; 3] });
+
+    let mut sender = Connection::new(TrickleStream::default(), Rotating { rotate: RotateBytes { amount: 0 } }, Settings::default());
+    sender.set_chunk_size(64);
+    sender.queue_packet(&asset, 1).unwrap();
+    sender.queue_packet(&queued, 0).unwrap();
+
+    // Switch halfway through the chunks of the first packet.
+    sender.send_queued().unwrap();
+    sender.send_queued().unwrap();
+    sender.switch(enable).unwrap();
+    sender.send_packet(&after).unwrap();
+    while !sender.send_queued().unwrap() {}
+
+    let mut receiver = Connection::new(Cursor::new(sender.stream.data.clone()), Rotating { rotate: RotateBytes { amount: 0 } }, Settings::default());
+    assert_eq!(receiver.receive_packet().unwrap(), Some(asset));
+    assert_eq!(receiver.receive_packet().unwrap(), Some(queued));
+
+    receiver.switch(enable).unwrap();
+    assert_eq!(receiver.receive_packet().unwrap(), Some(after));
+}
+
+#[test]
+fn batched_packets_are_written_before_queued_packets_when_switching() {
+    use protocol::wire::middleware::rotate_bytes::RotateBytes;
+    use protocol::wire::stream::Batching;
+
+    protocol::define_middleware_pipeline!(Rotating {
+        rotate: RotateBytes
+    });
+
+    let enable = |middleware: &mut Rotating, _: &mut Settings| {
+        middleware.rotate = RotateBytes::ROT13;
+    };
+
+    let batched = PacketKind::Ping(Ping { data: vec![1] });
+    let queued = PacketKind::Ping(Ping { data: vec![2; 2] });
+    let after = PacketKind::Ping(Ping { data: vec![3; 3] });
+
+    let mut sender = Connection::new(Cursor::new(Vec::new()), Rotating { rotate: RotateBytes { amount: 0 } }, Settings::default());
+    sender.set_batching(Some(Batching::default())).unwrap();
+    sender.send_packet(&batched).unwrap();
+    sender.queue_packet(&queued, 0).unwrap();
+
+    sender.switch(enable).unwrap();
+    assert_eq!(sender.batched_size(), 0);
+    sender.send_packet(&after).unwrap();
+    while !sender.send_queued().unwrap() {}
+    sender.flush().unwrap();
+
+    let mut receiver = Connection::new(Cursor::new(sender.stream.into_inner()), Rotating { rotate: RotateBytes { amount: 0 } }, Settings::default());
+    assert_eq!(receiver.receive_packet().unwrap(), Some(batched));
+    assert_eq!(receiver.receive_packet().unwrap(), Some(queued));
+
+    receiver.switch(enable).unwrap();
+    assert_eq!(receiver.receive_packet().unwrap(), Some(after));
+    assert_eq!(receiver.receive_packet().unwrap(), None);
+}
+
+#[test]
+fn sending_blocks_until_the_rate_limit_allows_it() {
+    use protocol::wire::rate_limit::{ManualClock, Mode, RateLimit, RateLimiter};
+    use protocol::wire::rate_limit::Clock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let clock = Arc::new(ManualClock::new());
+    let start = clock.now();
+    let limit = RateLimit { packets_per_second: Some(20), burst: Duration::from_millis(100), ..RateLimit::default() };
+
+    let mut connection: Connection<PacketKind, _> = Connection::new(Cursor::new(Vec::new()), middleware::pipeline::default(), Settings::default());
+    connection.rate_limiter = Some(RateLimiter::with_clock(limit, Mode::Block, clock.clone()));
+
+    // Two packets fit into the burst, and the rest are spaced out.
+    for _ in 0..12 {
+        connection.send_packet(&PacketKind::Ping(Ping { data: vec![1] })).unwrap();
+    }
+
+    let waited = clock.now() - start;
+    assert!(waited >= Duration::from_millis(500) && waited < Duration::from_millis(501), "{:?}", waited);
+}
+
+#[test]
+fn queued_packets_wait_for_a_rejecting_rate_limit() {
+    use protocol::wire::rate_limit::{ManualClock, Mode, RateLimit, RateLimiter};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let clock = Arc::new(ManualClock::new());
+    let limit = RateLimit { packets_per_second: Some(10), burst: Duration::from_millis(100), ..RateLimit::default() };
+
+    let mut connection: Connection<PacketKind, _> = Connection::new(Cursor::new(Vec::new()), middleware::pipeline::default(), Settings::default());
+    connection.rate_limiter = Some(RateLimiter::with_clock(limit, Mode::Reject, clock.clone()));
+
+    connection.queue_packet(&PacketKind::Ping(Ping { data: vec![1] }), 0).unwrap();
+    connection.queue_packet(&PacketKind::Ping(Ping { data: vec![2] }), 0).unwrap();
+
+    assert!(!connection.send_queued().unwrap());
+    assert!(connection.has_queued());
+
+    clock.advance(Duration::from_millis(100));
+    assert!(connection.send_queued().unwrap());
+
+    connection.stream.set_position(0);
+    assert_eq!(connection.receive_packet().unwrap(), Some(PacketKind::Ping(Ping { data: vec![1] })));
+    assert_eq!(connection.receive_packet().unwrap(), Some(PacketKind::Ping(Ping { data: vec![2] })));
+}